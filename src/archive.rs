@@ -0,0 +1,375 @@
+use crate::budget::Permit;
+use crate::config::{path_extension, strip_query_params, Config, CONTENT_TYPES};
+use crate::content_range::ContentRange;
+use crate::error::SegmentedCacheError as Error;
+use crate::fetch::{
+    fragment_from_response, fragment_request, FetchInfo, FragReqGen, FragmentFetcher,
+};
+use crate::headers::header_str;
+use crate::kv::{lookup_archive_index, save_archive_index, FragmentStore};
+use crate::log::with_log;
+use crate::range::{RequestRange, ResolvedRange};
+use crate::response::{range_not_satisfiable, set_range_headers, set_response_headers};
+use crate::stream::{stream_fragments, BodyStreamingState, Fragment, Validators};
+use crate::trace::TraceContext;
+use fastly::http::request::PendingRequest;
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use std::cmp::min;
+use std::io::Read;
+use std::time::Instant;
+
+#[derive(Clone, Copy)]
+pub(crate) enum ArchiveKind {
+    Tar,
+    Zip,
+}
+
+/// The kind of archive `req` addresses and the member it asks for by name.
+pub(crate) fn archive_member<'a>(
+    req: &'a Request,
+    config: &Config,
+) -> Option<(ArchiveKind, &'a str)> {
+    let ext = path_extension(req.get_path())?;
+    let (kind, param) = if ext.eq_ignore_ascii_case("tar") {
+        (ArchiveKind::Tar, config.tar_member_param.as_deref()?)
+    } else if ext.eq_ignore_ascii_case("zip") {
+        (ArchiveKind::Zip, config.zip_member_param.as_deref()?)
+    } else {
+        return None;
+    };
+    Some((kind, req.get_query_parameter(param)?))
+}
+
+/// Serves the member `name` of the archive `req` addresses as if it were an
+/// object of its own, `Range` included. The archive's member index, one
+/// `offset size name` line per member, comes from
+/// `Config::archive_index_store`, or is built from the archive and saved
+/// there.
+pub(crate) fn member_response(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    (kind, name): (ArchiveKind, &str),
+    header_only: bool,
+    resp_header_sent: &mut bool,
+    resp_headers: &[(String, String)],
+) -> Result<Option<Response>, Error> {
+    let mut archive = req.clone_without_body();
+    let param = match kind {
+        ArchiveKind::Tar => &config.tar_member_param,
+        ArchiveKind::Zip => &config.zip_member_param,
+    };
+    if let Some(param) = param {
+        strip_query_params(&mut archive, &[param]);
+    }
+
+    let mut reader = ArchiveReader::open(&archive, config, trace)?;
+    let index = match lookup_archive_index(&archive, config, &reader.validators) {
+        Some(index) => index,
+        None => {
+            let index = match kind {
+                ArchiveKind::Tar => crate::tar::build_index(&mut reader)?,
+                ArchiveKind::Zip => crate::zip::build_index(&mut reader)?,
+            };
+            save_archive_index(&archive, config, &reader.validators, &index);
+            index
+        }
+    };
+    let name = name.trim_start_matches("./");
+    let (offset, size) = match index.lines().find_map(|line| {
+        let mut fields = line.splitn(3, ' ');
+        let offset = fields.next()?.parse::<usize>().ok()?;
+        let size = fields.next()?.parse::<usize>().ok()?;
+        (fields.next()? == name).then_some((offset, size))
+    }) {
+        Some(member) => member,
+        None => {
+            return Ok(Some(
+                Response::from_status(StatusCode::NOT_FOUND)
+                    .with_body_text_plain("No such file in archive\n"),
+            ))
+        }
+    };
+    // ZIP indexes hold the offset of the member's local header, whose
+    // variable-length fields come before the data.
+    let offset = match kind {
+        ArchiveKind::Tar => offset,
+        ArchiveKind::Zip => crate::zip::data_offset(&mut reader, offset)?,
+    };
+    if offset
+        .checked_add(size)
+        .is_none_or(|end| end > reader.complete_length)
+    {
+        return Err(Error::Inconsistency(format!(
+            "member {} at {} of {} bytes past the end of the archive",
+            name, offset, size
+        )));
+    }
+
+    let mut resp = Response::from_status(StatusCode::OK);
+    let content_type = path_extension(name)
+        .and_then(|ext| {
+            CONTENT_TYPES
+                .iter()
+                .find(|(known, _)| known.eq_ignore_ascii_case(ext))
+        })
+        .map_or("application/octet-stream", |&(_, mime)| mime);
+    resp.set_header("content-type", content_type);
+    if let Some(last_modified) = &reader.validators.last_modified {
+        resp.set_header("last-modified", last_modified);
+    }
+    if size == 0 {
+        resp.set_header("content-length", "0");
+        return Ok(Some(resp));
+    }
+    let req_range = RequestRange::new(req, config)?;
+    let range = match ResolvedRange::new(&req_range, size) {
+        Some(range) => range,
+        None => return Ok(Some(range_not_satisfiable(size))),
+    };
+    set_range_headers(&mut resp, &req_range, &range, size, config);
+    if header_only {
+        return Ok(Some(resp));
+    }
+
+    // Past this point the member's range of the archive is streamed like
+    // any other range of an object.
+    let range = ResolvedRange {
+        first: offset + range.first,
+        last: offset + range.last,
+    };
+    let block_first = range.first / config.block_size * config.block_size;
+    let mut fetcher = FragmentFetcher::new(
+        &archive,
+        config,
+        trace,
+        FragReqGen::new(block_first, range.last, config),
+    );
+    fetcher.store =
+        FragmentStore::open(&archive, config, &reader.validators, reader.complete_length);
+    fetcher.dispatch()?;
+    let frag1 = match fetcher.next_response()? {
+        Some((beresp, info)) => {
+            fragment_from_response(beresp, &info, reader.complete_length, config)?
+        }
+        None => return Err(Error::Inconsistency(String::from("no fragment for member"))),
+    };
+    if !frag1.validators.matches(&reader.validators) {
+        return Err(Error::Inconsistency(String::from(
+            "archive changed while serving a member",
+        )));
+    }
+    resp.set_header("x-request-id", with_log(|log| log.request_id.clone()));
+    set_response_headers(&mut resp, resp_headers);
+    config.hooks.on_response_headers(&mut resp);
+    with_log(|log| log.status = Some(resp.get_status()));
+    let resp_body = resp.stream_to_client();
+    *resp_header_sent = true;
+    let mut state = BodyStreamingState::new(&range, resp_body, config);
+    let result = stream_fragments(&mut state, &mut fetcher, frag1, reader.complete_length);
+    fetcher.cancel();
+    let result = result.and(state.flush());
+    with_log(|log| log.bytes_sent = state.bytes_sent());
+    result?;
+    let _ = state.resp_body.finish();
+    Ok(None)
+}
+
+/// Blocks an `ArchiveReader` keeps, the most recently read last.
+const KEPT_BLOCKS: usize = 4;
+
+/// Reads an object a block at a time, keeping the last few blocks read so
+/// nearby reads don't fetch them again.
+pub(crate) struct ArchiveReader<'a> {
+    req: &'a Request,
+    pub(crate) config: &'a Config,
+    trace: &'a TraceContext,
+    pub(crate) complete_length: usize,
+    pub(crate) validators: Validators,
+    pub(crate) content_type: Option<String>,
+    /// The object is stored with a `Content-Encoding`, read as is.
+    pub(crate) encoded: bool,
+    #[cfg(feature = "decompression")]
+    pub(crate) no_transform: bool,
+    blocks: Vec<(usize, Vec<u8>)>,
+    pending: Vec<PendingBlock>,
+    /// Blocks fetched so far, no more than `Config::max_fragments`.
+    pub(crate) fetched: usize,
+}
+
+struct PendingBlock {
+    first: usize,
+    promise: PendingRequest,
+    permit: Permit,
+    range: String,
+    span_id: String,
+    sent: Instant,
+}
+
+impl<'a> ArchiveReader<'a> {
+    pub(crate) fn open(
+        req: &'a Request,
+        config: &'a Config,
+        trace: &'a TraceContext,
+    ) -> Result<Self, Error> {
+        let range = format!("bytes=0-{}", config.block_size - 1);
+        let (bereq, _) = fragment_request(req, config, trace, Method::GET, &range);
+        let beresp = bereq
+            .send(&config.backend_name)
+            .map_err(|e| Error::send("archive request send", e))?;
+        if beresp.get_status() != StatusCode::PARTIAL_CONTENT {
+            return Err(Error::OriginStatus(beresp.get_status()));
+        }
+        let content_range = ContentRange::new(&beresp, None, config)?;
+        let mut reader = ArchiveReader {
+            req,
+            config,
+            trace,
+            complete_length: content_range.complete_length,
+            validators: Validators::new(&beresp),
+            content_type: header_str(beresp.get_header("content-type")).map(String::from),
+            encoded: false,
+            #[cfg(feature = "decompression")]
+            no_transform: crate::headers::no_transform(&beresp),
+            blocks: Vec::new(),
+            pending: Vec::new(),
+            fetched: 1,
+        };
+        let block = read_fragment(Fragment::new(beresp, &content_range))?;
+        reader.blocks.push((0, block));
+        Ok(reader)
+    }
+
+    pub(crate) fn read(&mut self, position: usize, len: usize) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            let at = position + out.len();
+            let first = at / self.config.block_size * self.config.block_size;
+            let kept = match self.blocks.iter().position(|(kept, _)| *kept == first) {
+                Some(kept) => kept,
+                None => {
+                    self.fetch(first)?;
+                    self.blocks.len() - 1
+                }
+            };
+            let block = &self.blocks[kept].1;
+            let start = at - first;
+            if start >= block.len() {
+                return Err(Error::Truncated { offset: at });
+            }
+            let n = min(len - out.len(), block.len() - start);
+            out.extend_from_slice(&block[start..start + n]);
+        }
+        Ok(out)
+    }
+
+    /// Requests blocks that are about to be read, in order, so that up to
+    /// `Config::parallelism` are in flight. They take spare permits only, as
+    /// a member's fragments may be streamed while they are pending.
+    pub(crate) fn prefetch(&mut self, firsts: &[usize]) -> Result<(), Error> {
+        for &first in firsts {
+            if self.pending.len() >= self.config.parallelism
+                || self.fetched >= self.config.max_fragments
+            {
+                break;
+            }
+            if self.blocks.iter().any(|(kept, _)| *kept == first)
+                || self.pending.iter().any(|pending| pending.first == first)
+            {
+                continue;
+            }
+            let permit = match Permit::spare(self.config) {
+                Some(permit) => permit,
+                None => break,
+            };
+            self.fetched += 1;
+            let range = format!("bytes={}-{}", first, first + self.config.block_size - 1);
+            let (bereq, span_id) =
+                fragment_request(self.req, self.config, self.trace, Method::GET, &range);
+            let promise = bereq
+                .send_async(&self.config.backend_name)
+                .map_err(|e| Error::send("block request send_async", e))?;
+            self.pending.push(PendingBlock {
+                first,
+                promise,
+                permit,
+                range,
+                span_id,
+                sent: Instant::now(),
+            });
+        }
+        Ok(())
+    }
+
+    fn fetch(&mut self, first: usize) -> Result<(), Error> {
+        let pending = self
+            .pending
+            .iter()
+            .position(|pending| pending.first == first)
+            .map(|pending| self.pending.remove(pending));
+        // Held until the block is read. A block that wasn't prefetched is
+        // sent without one, taking the permit every prefetch leaves free.
+        let (mut beresp, range, span_id, sent, _permit) = match pending {
+            Some(PendingBlock {
+                promise,
+                permit,
+                range,
+                span_id,
+                sent,
+                ..
+            }) => {
+                let beresp = promise
+                    .wait()
+                    .map_err(|e| Error::send(format!("block request wait for {}", range), e))?;
+                (beresp, range, span_id, sent, Some(permit))
+            }
+            None => {
+                if self.fetched >= self.config.max_fragments {
+                    return Err(Error::Inconsistency(String::from("too many blocks read")));
+                }
+                self.fetched += 1;
+                let range = format!("bytes={}-{}", first, first + self.config.block_size - 1);
+                let (bereq, span_id) =
+                    fragment_request(self.req, self.config, self.trace, Method::GET, &range);
+                let sent = Instant::now();
+                let beresp = bereq
+                    .send(&self.config.backend_name)
+                    .map_err(|e| Error::send("block request send", e))?;
+                (beresp, range, span_id, sent, None)
+            }
+        };
+        if self.encoded {
+            beresp.remove_header("content-encoding");
+        }
+        let info = FetchInfo {
+            range,
+            span_id,
+            duration: sent.elapsed(),
+        };
+        let frag = fragment_from_response(beresp, &info, self.complete_length, self.config)?;
+        if frag.first != first || !frag.validators.matches(&self.validators) {
+            return Err(Error::Inconsistency(format!(
+                "object changed while reading it at {}",
+                first
+            )));
+        }
+        let block = read_fragment(frag)?;
+        if self.blocks.len() == KEPT_BLOCKS {
+            self.blocks.remove(0);
+        }
+        self.blocks.push((first, block));
+        Ok(())
+    }
+}
+
+fn read_fragment(mut frag: Fragment) -> Result<Vec<u8>, Error> {
+    let mut block = Vec::new();
+    frag.body.read_to_end(&mut block).map_err(Error::Read)?;
+    if block.len() != frag.range_last - frag.first + 1 {
+        return Err(Error::Truncated {
+            offset: frag.first + block.len(),
+        });
+    }
+    Ok(block)
+}
@@ -0,0 +1,370 @@
+use crate::config::{strip_query_params, wildcard_match, Config};
+use crate::headers::header_str;
+use crate::json::JsonValue;
+use fastly::secret_store::SecretStore;
+use fastly::Request;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Expiring signed URLs. A URL is valid until its `expires` parameter, a Unix
+/// time, when its `signature` parameter is the hex-encoded HMAC-SHA256 of
+/// the path and that time, separated by a newline. The query string is not
+/// signed.
+pub struct UrlSigning {
+    pub key: Vec<u8>,
+    pub expires_param: String,
+    pub signature_param: String,
+}
+
+impl UrlSigning {
+    /// Reads the key from an entry of a Secret Store.
+    pub fn from_secret_store(store: &str, name: &str) -> Option<Self> {
+        let key = SecretStore::open(store)
+            .ok()?
+            .get(name)?
+            .plaintext()
+            .to_vec();
+        Some(UrlSigning {
+            key,
+            expires_param: String::from("expires"),
+            signature_param: String::from("signature"),
+        })
+    }
+
+    /// Checks the signature and removes its parameters, so that every signed
+    /// URL for an object shares the object's cached blocks.
+    pub(crate) fn verify(&self, req: &mut Request) -> bool {
+        let expires = req
+            .get_query_parameter(&self.expires_param)
+            .map(String::from);
+        let signature = req
+            .get_query_parameter(&self.signature_param)
+            .and_then(unhex);
+        strip_query_params(req, &[&self.expires_param, &self.signature_param]);
+        let (expires, signature) = match (expires, signature) {
+            (Some(expires), Some(signature)) => (expires, signature),
+            _ => return false,
+        };
+        if !expires
+            .parse::<u64>()
+            .is_ok_and(|expires| expires > unix_now())
+        {
+            return false;
+        }
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(format!("{}\n{}", req.get_path(), expires).as_bytes());
+        mac.verify_slice(&signature).is_ok()
+    }
+}
+
+/// HS256 JSON Web Tokens, from an `Authorization: Bearer` header or, failing
+/// that, the named cookie. A token must be unexpired, meant for `audience`
+/// when that is set, and carry each of `required_claims` with the value
+/// given. A `path` claim limits the token to paths starting with it.
+pub struct JwtAuth {
+    pub key: Vec<u8>,
+    pub audience: Option<String>,
+    pub cookie: Option<String>,
+    pub required_claims: Vec<(String, String)>,
+    /// Seconds of clock skew allowed for `exp` and `nbf`.
+    pub leeway: u64,
+}
+
+impl JwtAuth {
+    /// Reads the HMAC key from an entry of a Secret Store.
+    pub fn from_secret_store(store: &str, name: &str) -> Option<Self> {
+        let key = SecretStore::open(store)
+            .ok()?
+            .get(name)?
+            .plaintext()
+            .to_vec();
+        Some(JwtAuth {
+            key,
+            audience: None,
+            cookie: None,
+            required_claims: Vec::new(),
+            leeway: 60,
+        })
+    }
+
+    /// Checks the token, and keeps a bearer token away from the origin and
+    /// the caches once it has been accepted.
+    pub(crate) fn verify(&self, req: &mut Request) -> bool {
+        let bearer = header_str(req.get_header("authorization"))
+            .and_then(|value| value.trim().split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| String::from(token.trim()));
+        let token = match bearer
+            .clone()
+            .or_else(|| cookie(req, self.cookie.as_deref()?))
+        {
+            Some(token) => token,
+            None => return false,
+        };
+        let valid = self
+            .claims(&token)
+            .is_some_and(|claims| self.accepts(&claims, req.get_path()));
+        if valid && bearer.is_some() {
+            req.remove_header("authorization");
+        }
+        valid
+    }
+
+    fn claims(&self, token: &str) -> Option<JsonValue> {
+        let (signed, signature) = token.rsplit_once('.')?;
+        let (header, payload) = signed.split_once('.')?;
+        let header = JsonValue::parse(&String::from_utf8(base64url(header)?).ok()?).ok()?;
+        // Only HS256 is accepted, whatever else the token says it is.
+        if header.get("alg").and_then(JsonValue::as_str) != Some("HS256") {
+            return None;
+        }
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(signed.as_bytes());
+        mac.verify_slice(&base64url(signature)?).ok()?;
+        JsonValue::parse(&String::from_utf8(base64url(payload)?).ok()?).ok()
+    }
+
+    fn accepts(&self, claims: &JsonValue, path: &str) -> bool {
+        let now = unix_now();
+        match claims.get("exp").and_then(seconds) {
+            Some(exp) if exp.saturating_add(self.leeway) > now => (),
+            _ => return false,
+        }
+        if let Some(nbf) = claims.get("nbf") {
+            if seconds(nbf).is_none_or(|nbf| nbf > now.saturating_add(self.leeway)) {
+                return false;
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let meant = match claims.get("aud") {
+                Some(JsonValue::String(aud)) => aud == audience,
+                Some(JsonValue::Array(auds)) => auds
+                    .iter()
+                    .any(|aud| aud.as_str() == Some(audience.as_str())),
+                _ => false,
+            };
+            if !meant {
+                return false;
+            }
+        }
+        if let Some(prefix) = claims.get("path") {
+            if !prefix
+                .as_str()
+                .is_some_and(|prefix| path.starts_with(prefix))
+            {
+                return false;
+            }
+        }
+        self.required_claims
+            .iter()
+            .all(|(name, value)| match claims.get(name) {
+                Some(JsonValue::String(claim)) | Some(JsonValue::Number(claim)) => claim == value,
+                Some(JsonValue::True) => value == "true",
+                Some(JsonValue::False) => value == "false",
+                _ => false,
+            })
+    }
+}
+
+/// Signed cookies in the style of CloudFront custom policies, so that
+/// players which can't attach a token to every range request are authorized
+/// once per session. The policy cookie holds a base64url-encoded policy, and
+/// the signature cookie the base64url-encoded HMAC-SHA256 of the policy
+/// cookie's value.
+///
+/// Any statement of the policy may grant the request. A statement needs a
+/// `Resource`, a URL or path where `*` matches any run of characters, and a
+/// `DateLessThan` time. `DateGreaterThan` and an `IpAddress` CIDR block are
+/// checked when present.
+pub struct SignedCookies {
+    pub key: Vec<u8>,
+    pub policy_cookie: String,
+    pub signature_cookie: String,
+}
+
+impl SignedCookies {
+    /// Reads the HMAC key from an entry of a Secret Store.
+    pub fn from_secret_store(store: &str, name: &str) -> Option<Self> {
+        let key = SecretStore::open(store)
+            .ok()?
+            .get(name)?
+            .plaintext()
+            .to_vec();
+        Some(SignedCookies {
+            key,
+            policy_cookie: String::from("sc-policy"),
+            signature_cookie: String::from("sc-signature"),
+        })
+    }
+
+    pub(crate) fn verify(&self, req: &Request) -> bool {
+        let policy = match self.policy(req) {
+            Some(policy) => policy,
+            None => return false,
+        };
+        let statements = match policy.get("Statement") {
+            Some(JsonValue::Array(statements)) => statements,
+            _ => return false,
+        };
+        statements.iter().any(|statement| grants(statement, req))
+    }
+
+    fn policy(&self, req: &Request) -> Option<JsonValue> {
+        let policy = cookie(req, &self.policy_cookie)?;
+        let signature = base64url(&cookie(req, &self.signature_cookie)?)?;
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(policy.as_bytes());
+        mac.verify_slice(&signature).ok()?;
+        JsonValue::parse(&String::from_utf8(base64url(&policy)?).ok()?).ok()
+    }
+}
+
+fn grants(statement: &JsonValue, req: &Request) -> bool {
+    let condition = |name: &str, key: &str| {
+        statement
+            .get("Condition")
+            .and_then(|condition| condition.get(name))
+            .and_then(|condition| condition.get(key))
+    };
+    let now = unix_now();
+    let resource = match statement.get("Resource").and_then(JsonValue::as_str) {
+        Some(resource) => resource,
+        None => return false,
+    };
+    // A URL resource is matched on its path; the host is this service's.
+    let resource = match resource.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |slash| &rest[slash..]),
+        None => resource,
+    };
+    if !wildcard_match(resource, req.get_path()) {
+        return false;
+    }
+    match condition("DateLessThan", "AWS:EpochTime").and_then(seconds) {
+        Some(expires) if now < expires => (),
+        _ => return false,
+    }
+    if let Some(start) = condition("DateGreaterThan", "AWS:EpochTime") {
+        if seconds(start).is_none_or(|start| now <= start) {
+            return false;
+        }
+    }
+    if let Some(cidr) = condition("IpAddress", "AWS:SourceIp") {
+        let client = req.get_client_ip_addr();
+        if !cidr
+            .as_str()
+            .zip(client)
+            .is_some_and(|(cidr, client)| cidr_contains(cidr, client))
+        {
+            return false;
+        }
+    }
+    true
+}
+
+fn cidr_contains(cidr: &str, client: IpAddr) -> bool {
+    let (network, prefix) = match cidr.split_once('/') {
+        Some((network, prefix)) => (network, prefix.parse::<u32>().ok()),
+        None => (cidr, None),
+    };
+    // IPv4 addresses sit in the top bits, so one mask serves both families.
+    let (network, client, width) = match (network.parse::<IpAddr>(), client) {
+        (Ok(IpAddr::V4(network)), IpAddr::V4(client)) => (
+            u128::from(u32::from(network)) << 96,
+            u128::from(u32::from(client)) << 96,
+            32,
+        ),
+        (Ok(IpAddr::V6(network)), IpAddr::V6(client)) => {
+            (u128::from(network), u128::from(client), 128)
+        }
+        _ => return false,
+    };
+    let prefix = match prefix {
+        Some(prefix) if prefix <= width => prefix,
+        Some(_) => return false,
+        None => width,
+    };
+    let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+    network & mask == client & mask
+}
+
+/// Checks a request against every configured scheme, before anything is
+/// fetched for it.
+pub(crate) fn authorize(req: &mut Request, config: &Config) -> bool {
+    if let Some(signing) = &config.url_signing {
+        if !signing.verify(req) {
+            return false;
+        }
+    }
+    if let Some(jwt) = &config.jwt {
+        if !jwt.verify(req) {
+            return false;
+        }
+    }
+    if let Some(cookies) = &config.signed_cookies {
+        if !cookies.verify(req) {
+            return false;
+        }
+    }
+    true
+}
+
+fn cookie(req: &Request, name: &str) -> Option<String> {
+    req.get_header_all_str("cookie")
+        .iter()
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| String::from(value))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn seconds(value: &JsonValue) -> Option<u64> {
+    match value {
+        // NumericDate allows a fraction.
+        JsonValue::Number(value) => value.split('.').next()?.parse().ok(),
+        _ => None,
+    }
+}
+
+fn base64url(value: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(value.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in value.trim_end_matches('=').bytes() {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | u32::from(sextet);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+fn unhex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,138 @@
+//! Replays the segmentation against an origin from a workstation, to compare
+//! block sizes and parallelism before deploying.
+//!
+//!     simulate URL [BLOCK_SIZES] [PARALLELISM]
+//!
+//! Lists are comma-separated. Sizes take a K or M suffix. The defaults are
+//! 512K,1M,2M,4M and 1,5,10.
+
+use reqwest::blocking::Client;
+use reqwest::StatusCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Run {
+    discovery: Duration,
+    total: Duration,
+    fragments: usize,
+    fetch_total: Duration,
+    bytes: usize,
+}
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    let url = match args.get(1) {
+        Some(url) => url,
+        None => {
+            eprintln!("usage: simulate URL [BLOCK_SIZES] [PARALLELISM]");
+            std::process::exit(2);
+        }
+    };
+    let block_sizes = parse_list(args.get(2).map_or("512K,1M,2M,4M", String::as_str));
+    let parallelisms = parse_list(args.get(3).map_or("1,5,10", String::as_str));
+    let client = Client::new();
+    println!("block_size\tparallelism\tfragments\tdiscovery_ms\tfetch_avg_ms\ttotal_ms\tMB/s");
+    for &block_size in &block_sizes {
+        for &parallelism in &parallelisms {
+            match simulate(&client, url, block_size, parallelism) {
+                Ok(run) => println!(
+                    "{}\t{}\t{}\t{:.1}\t{:.1}\t{:.1}\t{:.2}",
+                    block_size,
+                    parallelism,
+                    run.fragments,
+                    run.discovery.as_secs_f64() * 1000.0,
+                    run.fetch_total.as_secs_f64() * 1000.0 / run.fragments as f64,
+                    run.total.as_secs_f64() * 1000.0,
+                    run.bytes as f64 / run.total.as_secs_f64() / 1e6,
+                ),
+                Err(e) => println!("{}\t{}\terror: {}", block_size, parallelism, e),
+            }
+        }
+    }
+}
+
+fn parse_list(list: &str) -> Vec<usize> {
+    list.split(",")
+        .filter_map(|item| {
+            let item = item.trim();
+            let (digits, scale) = match item.as_bytes().last() {
+                Some(b'K') | Some(b'k') => (&item[..item.len() - 1], 1024),
+                Some(b'M') | Some(b'm') => (&item[..item.len() - 1], 1024 * 1024),
+                _ => (item, 1),
+            };
+            digits.parse::<usize>().ok().map(|value| value * scale)
+        })
+        .filter(|&value| value > 0)
+        .collect()
+}
+
+fn fetch(client: &Client, url: &str, first: usize, last: usize) -> Result<(usize, usize), String> {
+    let resp = client
+        .get(url)
+        .header("range", format!("bytes={}-{}", first, last))
+        .send()
+        .map_err(|e| e.to_string())?;
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(format!("status {} rather than 206", resp.status()));
+    }
+    let complete_length = resp
+        .headers()
+        .get("content-range")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit_once("/"))
+        .and_then(|(_, length)| length.trim().parse().ok())
+        .ok_or("no complete length in content-range")?;
+    let body = resp.bytes().map_err(|e| e.to_string())?;
+    Ok((body.len(), complete_length))
+}
+
+fn simulate(
+    client: &Client,
+    url: &str,
+    block_size: usize,
+    parallelism: usize,
+) -> Result<Run, String> {
+    let start = Instant::now();
+    let (bytes, complete_length) = fetch(client, url, 0, block_size - 1)?;
+    let discovery = start.elapsed();
+    let blocks = complete_length.div_ceil(block_size);
+    let next = AtomicUsize::new(1);
+    let stats = Mutex::new((1, discovery, bytes, None));
+    std::thread::scope(|scope| {
+        for _ in 0..parallelism {
+            scope.spawn(|| loop {
+                let block = next.fetch_add(1, Ordering::Relaxed);
+                if block >= blocks {
+                    break;
+                }
+                let first = block * block_size;
+                let sent = Instant::now();
+                let result = fetch(client, url, first, first + block_size - 1);
+                let mut stats = stats.lock().unwrap();
+                match result {
+                    Ok((bytes, _)) => {
+                        stats.0 += 1;
+                        stats.1 += sent.elapsed();
+                        stats.2 += bytes;
+                    }
+                    Err(e) => {
+                        stats.3 = Some(e);
+                        next.store(blocks, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    let (fragments, fetch_total, bytes, error) = stats.into_inner().unwrap();
+    if let Some(e) = error {
+        return Err(e);
+    }
+    Ok(Run {
+        discovery,
+        total: start.elapsed(),
+        fragments,
+        fetch_total,
+        bytes,
+    })
+}
@@ -0,0 +1,84 @@
+use crate::config::Config;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+thread_local! {
+    /// Requests sent to each backend whose responses haven't been read yet.
+    static IN_FLIGHT: RefCell<HashMap<String, usize>> = RefCell::new(HashMap::new());
+}
+
+/// One request to a backend counted against `Config::backend_max_concurrency`
+/// until the permit is dropped, which for a fragment is once its response
+/// arrives. The fragment being streamed isn't counted.
+///
+/// Requests sent with `send_async` take a permit and leave one short of the
+/// limit free, so a blocking `send`, such as a resume, can always go out
+/// without one and the total stays within the limit. Prefetches the
+/// response doesn't wait for leave one more, so they never take the last
+/// permit the fragments being streamed need.
+///
+/// The count is kept per client request, in the instance serving it. It
+/// doesn't bound what all the instances together have open to a backend;
+/// the backend's own connection limit in the service does that. With a
+/// limit of 1 there is nothing to leave free, so a blocking `send`, and a
+/// fragment forced out by `force`, may go over it by one.
+pub(crate) struct Permit {
+    backend: String,
+}
+
+impl Permit {
+    /// A permit for a fragment or probe the response is built from.
+    pub(crate) fn try_acquire(config: &Config) -> Option<Self> {
+        Permit::below(
+            config,
+            config.backend_max_concurrency.saturating_sub(1).max(1),
+        )
+    }
+
+    /// A permit for a block prefetched ahead of the client's requests.
+    pub(crate) fn spare(config: &Config) -> Option<Self> {
+        Permit::below(config, config.backend_max_concurrency.saturating_sub(2))
+    }
+
+    /// A permit for a request that has to go out for the response to make
+    /// any progress, counted even over the limit.
+    pub(crate) fn force(config: &Config) -> Self {
+        IN_FLIGHT.with(|in_flight| {
+            *in_flight
+                .borrow_mut()
+                .entry(config.backend_name.clone())
+                .or_insert(0) += 1;
+        });
+        Permit {
+            backend: config.backend_name.clone(),
+        }
+    }
+
+    fn below(config: &Config, cap: usize) -> Option<Self> {
+        if in_flight(config) >= cap {
+            return None;
+        }
+        Some(Permit::force(config))
+    }
+}
+
+/// Requests to `config.backend_name` holding a permit.
+pub(crate) fn in_flight(config: &Config) -> usize {
+    IN_FLIGHT.with(|in_flight| {
+        in_flight
+            .borrow()
+            .get(&config.backend_name)
+            .copied()
+            .unwrap_or(0)
+    })
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|in_flight| {
+            if let Some(count) = in_flight.borrow_mut().get_mut(&self.backend) {
+                *count = count.saturating_sub(1);
+            }
+        });
+    }
+}
@@ -0,0 +1,33 @@
+use crate::log::random_u64;
+use fastly::http::StatusCode;
+use fastly::Response;
+use std::time::Duration;
+
+/// Replaces a fragment response with a fault for a `rate` share of calls.
+/// Delays, truncated bodies, 503s and malformed content ranges are equally
+/// likely.
+pub(crate) fn inject(mut beresp: Response, rate: f64) -> Response {
+    if (random_u64() as f64) >= rate * (u64::MAX as f64) {
+        return beresp;
+    }
+    match random_u64() % 4 {
+        0 => {
+            std::thread::sleep(Duration::from_millis(random_u64() % 2000));
+            beresp
+        }
+        1 => {
+            // Without a content length the short body is only noticed once
+            // it runs out, like a dropped origin connection.
+            let mut body = beresp.take_body_bytes();
+            body.truncate(body.len() / 2);
+            beresp.remove_header("content-length");
+            beresp.set_body(body);
+            beresp
+        }
+        2 => Response::from_status(StatusCode::SERVICE_UNAVAILABLE),
+        _ => {
+            beresp.set_header("content-range", "bytes chaos");
+            beresp
+        }
+    }
+}
@@ -0,0 +1,804 @@
+use crate::headers::header_str;
+use crate::hooks::{Hooks, NoHooks};
+use crate::json::JsonValue;
+use crate::rate_limit::RateLimit;
+use fastly::config_store::ConfigStore;
+use fastly::geo::geo_lookup;
+use fastly::http::HeaderValue;
+use fastly::secret_store::SecretStore;
+use fastly::Request;
+use std::cmp::min;
+use std::time::Duration;
+
+pub struct Config {
+    pub block_size: usize,
+    pub parallelism: usize,
+    pub read_chunk_size: usize,
+    pub write_flush_size: usize,
+    pub backend_name: String,
+    pub backend_host: HeaderValue,
+    pub backend_max_concurrency: usize,
+    pub max_resumes: usize,
+    pub lenient_content_range: bool,
+    pub degrade_after_failures: usize,
+    pub max_object_size: Option<usize>,
+    pub max_fragments: usize,
+    /// The part size of the origin's multipart uploads. Blocks are cut so
+    /// that none spans two parts.
+    pub origin_part_size: Option<usize>,
+    /// Infers the part size from S3 multipart ETags, `"<md5>-<parts>"`.
+    pub detect_part_size: bool,
+    pub uncached_prefixes: Vec<String>,
+    /// Bounds on how long the VCL service caches a block, whatever the
+    /// origin's `Cache-Control` says. Sent along with each block request.
+    pub fragment_ttl_max: Option<Duration>,
+    pub fragment_ttl_min: Option<Duration>,
+    /// `Surrogate-Control` values for the VCL service to cache blocks by, by
+    /// path pattern with `*` wildcards, such as `("/releases/*",
+    /// "max-age=31536000, stale-if-error=86400")`. The first matching
+    /// pattern wins. Clients still get the origin's `Cache-Control`.
+    pub fragment_surrogate_control: Vec<(String, String)>,
+    pub passthrough_prefixes: Vec<String>,
+    /// Path prefixes of objects the origin appends to, such as live DVR
+    /// recordings. Open-ended ranges of them follow the object as it grows.
+    pub live_prefixes: Vec<String>,
+    pub live_budget: Duration,
+    pub live_max_bytes: usize,
+    pub live_poll_interval: Duration,
+    /// Path prefixes replaced in origin requests, such as `/dl/v2/` with
+    /// `/artifacts/`. The first matching prefix wins.
+    pub path_rewrites: Vec<(String, String)>,
+    /// Query parameters added to origin requests, already percent-encoded.
+    /// Clients can't set them, and they never reach the cache keys here.
+    pub origin_query_params: Vec<(String, String)>,
+    pub log_endpoint: Option<String>,
+    pub log_fragments: bool,
+    pub log_error_sample_rate: f64,
+    pub log_success_sample_rate: f64,
+    pub metrics_endpoint: Option<String>,
+    pub log_tags: Vec<(String, String)>,
+    pub merge_identical_ranges: bool,
+    pub merge_overlapping_ranges: bool,
+    /// Ranges that don't merge, up to this many, are served as one
+    /// `multipart/byteranges` response. Otherwise the whole object is sent.
+    pub multipart_ranges: usize,
+    /// Ranges of a multipart response closer together than this many bytes,
+    /// about the size of a part's headers, are sent as one part.
+    pub multipart_coalesce_gap: usize,
+    pub debug_token: Option<String>,
+    pub cors_allowed_origins: Vec<String>,
+    pub cors_allowed_methods: String,
+    pub cors_allowed_headers: String,
+    pub cors_max_age: u32,
+    pub cors_expose_headers: String,
+    pub advertise_block_size: bool,
+    pub weak_etags: bool,
+    /// Compresses responses for the whole object at the edge, for clients
+    /// that accept it and content types starting with one of
+    /// `compressible_types`.
+    pub compress_full_responses: bool,
+    pub compressible_types: Vec<String>,
+    /// Encodings and the path suffixes of their precompressed siblings at the
+    /// origin, such as `("br", ".br")`, served in place of compressible
+    /// objects to clients that accept them. Tried in order.
+    pub precompressed_siblings: Vec<(String, String)>,
+    pub download_param: String,
+    pub filename_param: String,
+    pub content_disposition: Option<String>,
+    pub fallback_content_type: Option<String>,
+    pub via: Option<String>,
+    pub served_by: bool,
+    pub timing_allow_origin: Option<String>,
+    pub only_if_cached_header: Option<String>,
+    pub forwarded_response_headers: Vec<String>,
+    pub security_headers: Vec<(String, String)>,
+    pub hooks: Box<dyn Hooks>,
+    pub chaos_token: Option<String>,
+    pub chaos_rate: f64,
+    pub self_test: Option<SelfTest>,
+    pub kv_fragment_store: Option<String>,
+    /// An origin response header carrying a digest of the object's bytes,
+    /// such as `x-amz-meta-sha256`. Objects with the same digest share
+    /// their cached blocks.
+    pub content_digest_header: Option<String>,
+    pub kv_head_blocks: usize,
+    pub kv_manifest_store: Option<String>,
+    pub background_fill_blocks: usize,
+    pub background_fill_budget: Duration,
+    pub prewarm_manifest: Option<String>,
+    /// Blocks one prewarm request fetches at most, across every object it
+    /// warms.
+    pub prewarm_max_blocks: usize,
+    /// Blocks at the start and end of an MP4 fetched along with any range
+    /// of it, since players read the `moov` atom before playing.
+    pub mp4_head_blocks: usize,
+    pub mp4_tail_blocks: usize,
+    /// Blocks at the end of a ZIP, JAR or APK fetched along with its head,
+    /// for the central directory.
+    pub zip_tail_blocks: usize,
+    /// Read-ahead for formats with structured random access, by extension.
+    /// They take precedence over the MP4 and ZIP read-ahead.
+    pub readahead_profiles: Vec<ReadaheadProfile>,
+    /// Segments of an HLS or DASH playlist announced in `Link` preload hints.
+    pub playlist_preload_segments: usize,
+    pub sequence_prewarm: Vec<SequencePrewarm>,
+    /// The query parameter naming a member of a `.tar` archive to serve.
+    pub tar_member_param: Option<String>,
+    /// The query parameter naming a member of a `.zip` archive to serve.
+    pub zip_member_param: Option<String>,
+    /// A KV Store for the member indexes of tar and ZIP archives.
+    pub archive_index_store: Option<String>,
+    /// A KV Store of manifests, keyed by path, for objects served as the
+    /// concatenation of other objects.
+    pub virtual_object_store: Option<String>,
+    pub rate_limit: Option<RateLimit>,
+    pub hotlink_allowed_hosts: Vec<String>,
+    pub hotlink_allow_missing: bool,
+    pub hotlink_redirect: Option<String>,
+    pub regional_backends: Vec<RegionalBackend>,
+    /// A Config Store mapping the incoming `Host` to a tenant.
+    pub tenant_store: Option<String>,
+    pub tenant_profiles: Vec<TenantProfile>,
+    pub local: bool,
+    #[cfg(feature = "object-storage")]
+    pub object_storage: Option<crate::object_storage::ObjectStorage>,
+    #[cfg(feature = "auth")]
+    pub url_signing: Option<crate::auth::UrlSigning>,
+    #[cfg(feature = "auth")]
+    pub jwt: Option<crate::auth::JwtAuth>,
+    #[cfg(feature = "auth")]
+    pub signed_cookies: Option<crate::auth::SignedCookies>,
+    #[cfg(feature = "encryption")]
+    pub encryption: Option<crate::encryption::Encryption>,
+    /// Path prefixes of objects the origin stores gzip-compressed, served
+    /// decompressed.
+    #[cfg(feature = "decompression")]
+    pub gzip_prefixes: Vec<String>,
+    /// Checks whole-object responses against the SHA-256 in an
+    /// `x-sc-expected-digest` request header. Only for services whose
+    /// upstream sets that header itself, replacing any from the client.
+    #[cfg(feature = "integrity")]
+    pub trust_expected_digest: bool,
+}
+
+/// An origin replica for the clients in some regions.
+pub struct RegionalBackend {
+    /// ISO 3166 country codes, such as `JP`, or continent codes, such as
+    /// `EU`.
+    pub regions: Vec<String>,
+    pub backend: String,
+    pub host: HeaderValue,
+}
+
+/// Settings applied for the tenants naming the profile.
+pub struct TenantProfile {
+    pub name: String,
+    pub apply: fn(&mut Config),
+}
+
+/// How a format is read, as the blocks at each end of an object those
+/// reading it start from.
+pub struct ReadaheadProfile {
+    pub extensions: Vec<String>,
+    pub head_blocks: usize,
+    pub tail_blocks: usize,
+    /// A block size suiting the format's access pattern.
+    pub block_size: Option<usize>,
+}
+
+impl ReadaheadProfile {
+    pub fn new(extensions: &[&str], head_blocks: usize, tail_blocks: usize) -> Self {
+        ReadaheadProfile {
+            extensions: extensions.iter().map(|&ext| String::from(ext)).collect(),
+            head_blocks,
+            tail_blocks,
+            block_size: None,
+        }
+    }
+}
+
+/// Objects numbered in sequence, such as `/vod/*/segment_{n}.ts`, where a
+/// response for one is followed by warming the first `blocks` blocks of the
+/// next. `*` matches anything and `{n}` the number, whose width the next
+/// one keeps.
+pub struct SequencePrewarm {
+    pub pattern: String,
+    pub blocks: usize,
+}
+
+/// A known object fetched through the service by `/__sc/self-test`.
+pub struct SelfTest {
+    /// A backend pointing back at this service.
+    pub backend: String,
+    pub host: String,
+    pub path: String,
+    pub length: usize,
+    /// Hex-encoded SHA-256 of the object.
+    pub sha256: String,
+}
+
+impl Config {
+    pub fn new(req: &Request) -> Self {
+        let mut block_size = 1024 * 1024;
+        let mut parallelism = 5;
+        let mut read_chunk_size = 65536;
+        let mut write_flush_size = 65536;
+        if let Some(field) = req.get_header("x-sc-conf").and_then(|hv| hv.to_str().ok()) {
+            for part in field.split(",") {
+                match part.split_once("=") {
+                    Some(("b", value)) => {
+                        if let Ok(value) = value.parse() {
+                            if 1024 * 1024 <= value && value <= 50 * 1024 * 1024 {
+                                block_size = value;
+                            }
+                        }
+                    }
+                    Some(("p", value)) => {
+                        if let Ok(value) = value.parse() {
+                            if 1 <= value && value <= 10 {
+                                parallelism = value;
+                            }
+                        }
+                    }
+                    Some(("r", value)) => {
+                        if let Ok(value) = value.parse() {
+                            if 1024 <= value && value <= 1024 * 1024 {
+                                read_chunk_size = value;
+                            }
+                        }
+                    }
+                    Some(("w", value)) => {
+                        if let Ok(value) = value.parse() {
+                            if 1024 <= value && value <= 1024 * 1024 {
+                                write_flush_size = value;
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        let download_param = String::from("download");
+        let filename_param = String::from("filename");
+        let content_disposition = content_disposition(req, &download_param, &filename_param);
+        let fallback_content_type = path_extension(req.get_path())
+            .and_then(|ext| {
+                CONTENT_TYPES
+                    .iter()
+                    .find(|(known, _)| known.eq_ignore_ascii_case(ext))
+            })
+            .map(|&(_, mime)| String::from(mime));
+        let mut config = Self {
+            block_size,
+            parallelism,
+            read_chunk_size,
+            write_flush_size,
+            backend_name: String::from("YOUR_BACKEND_NAME_HERE"),
+            backend_host: HeaderValue::from_static("the.host.header.for.your.backend.here"),
+            backend_max_concurrency: 10,
+            max_resumes: 3,
+            lenient_content_range: false,
+            degrade_after_failures: 3,
+            max_object_size: None,
+            max_fragments: 10000,
+            origin_part_size: None,
+            detect_part_size: false,
+            uncached_prefixes: Vec::new(),
+            fragment_ttl_max: None,
+            fragment_ttl_min: None,
+            fragment_surrogate_control: Vec::new(),
+            passthrough_prefixes: Vec::new(),
+            live_prefixes: Vec::new(),
+            live_budget: Duration::from_secs(30),
+            live_max_bytes: 1 << 30,
+            live_poll_interval: Duration::from_secs(1),
+            path_rewrites: Vec::new(),
+            origin_query_params: Vec::new(),
+            log_endpoint: None,
+            log_fragments: false,
+            log_error_sample_rate: 1.0,
+            log_success_sample_rate: 0.01,
+            metrics_endpoint: None,
+            log_tags: Vec::new(),
+            merge_identical_ranges: true,
+            merge_overlapping_ranges: true,
+            multipart_ranges: 0,
+            multipart_coalesce_gap: 128,
+            debug_token: None,
+            cors_allowed_origins: Vec::new(),
+            cors_allowed_methods: String::from("GET, HEAD"),
+            cors_allowed_headers: String::from("range"),
+            cors_max_age: 86400,
+            cors_expose_headers: String::from("content-range, content-length, accept-ranges"),
+            advertise_block_size: false,
+            weak_etags: false,
+            compress_full_responses: false,
+            compressible_types: [
+                "text/",
+                "application/json",
+                "application/x-ndjson",
+                "application/xml",
+                "application/javascript",
+                "application/sql",
+                "image/svg+xml",
+            ]
+            .iter()
+            .map(|&prefix| String::from(prefix))
+            .collect(),
+            precompressed_siblings: Vec::new(),
+            download_param,
+            filename_param,
+            content_disposition,
+            fallback_content_type,
+            via: Some(String::from("1.1 compute-segmented-caching")),
+            served_by: false,
+            timing_allow_origin: None,
+            only_if_cached_header: None,
+            forwarded_response_headers: [
+                "age",
+                "cache-control",
+                "content-disposition",
+                "content-language",
+                "content-type",
+                "date",
+                "etag",
+                "expires",
+                "last-modified",
+                "vary",
+            ]
+            .iter()
+            .map(|&name| String::from(name))
+            .collect(),
+            security_headers: Vec::new(),
+            hooks: Box::new(NoHooks),
+            chaos_token: None,
+            chaos_rate: 0.1,
+            self_test: None,
+            kv_fragment_store: None,
+            content_digest_header: None,
+            kv_head_blocks: 4,
+            kv_manifest_store: None,
+            background_fill_blocks: 0,
+            background_fill_budget: Duration::from_secs(10),
+            prewarm_manifest: None,
+            prewarm_max_blocks: 100000,
+            mp4_head_blocks: 1,
+            mp4_tail_blocks: 1,
+            zip_tail_blocks: 2,
+            readahead_profiles: vec![
+                // Cloud-optimized GeoTIFFs keep their IFDs and tile offsets
+                // at the head.
+                ReadaheadProfile::new(&["tif", "tiff"], 1, 0),
+                // Parquet readers start from the footer's metadata.
+                ReadaheadProfile::new(&["parquet"], 0, 1),
+                // The BAM header, and the BGZF end-of-file marker readers
+                // check for.
+                ReadaheadProfile::new(&["bam"], 1, 1),
+                // The SQLite header and the schema's root pages.
+                ReadaheadProfile::new(&["mbtiles"], 2, 0),
+            ],
+            playlist_preload_segments: 3,
+            sequence_prewarm: Vec::new(),
+            tar_member_param: None,
+            zip_member_param: None,
+            archive_index_store: None,
+            virtual_object_store: None,
+            rate_limit: None,
+            hotlink_allowed_hosts: Vec::new(),
+            hotlink_allow_missing: true,
+            hotlink_redirect: None,
+            regional_backends: Vec::new(),
+            tenant_store: None,
+            tenant_profiles: Vec::new(),
+            local: running_locally(),
+            #[cfg(feature = "object-storage")]
+            object_storage: None,
+            #[cfg(feature = "auth")]
+            url_signing: None,
+            #[cfg(feature = "auth")]
+            jwt: None,
+            #[cfg(feature = "auth")]
+            signed_cookies: None,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            #[cfg(feature = "decompression")]
+            gzip_prefixes: Vec::new(),
+            #[cfg(feature = "integrity")]
+            trust_expected_digest: false,
+        };
+        if config.local {
+            config.apply_local_profile();
+        }
+        config
+    }
+
+    /// Applies the settings that depend on both the request and the rest of
+    /// the configuration, so `segmented_fetch` calls it once the embedder is
+    /// done setting fields.
+    pub(crate) fn select_for_request(&mut self, req: &Request) {
+        // Running locally, every request goes to the local origin.
+        if !self.local {
+            self.select_tenant(req);
+        }
+        if let Some(block_size) = self
+            .readahead_profile(req.get_path())
+            .and_then(|profile| profile.block_size)
+        {
+            self.block_size = block_size;
+        }
+        if !self.local {
+            self.select_regional_backend(req);
+        }
+    }
+
+    /// Looks the request's `Host` up in `tenant_store`, whose values are
+    /// JSON objects with a `backend`, an optional origin `host` and an
+    /// optional `profile` naming one of `tenant_profiles`. The origin host
+    /// defaults to the incoming one. Hosts without an entry keep the
+    /// defaults.
+    fn select_tenant(&mut self, req: &Request) {
+        let store = match self.tenant_store.as_deref().map(ConfigStore::try_open) {
+            Some(Ok(store)) => store,
+            _ => return,
+        };
+        let host = match header_str(req.get_header("host")) {
+            Some(host) => host.rsplit_once(':').map_or(host, |(name, _)| name),
+            None => return,
+        };
+        let host = host.to_ascii_lowercase();
+        let tenant = match store
+            .get(&host)
+            .and_then(|value| JsonValue::parse(&value).ok())
+        {
+            Some(tenant) => tenant,
+            None => return,
+        };
+        let backend = match tenant.get("backend").and_then(JsonValue::as_str) {
+            Some(backend) => backend,
+            None => return,
+        };
+        let backend_host = tenant
+            .get("host")
+            .and_then(JsonValue::as_str)
+            .unwrap_or(&host);
+        let backend_host = match HeaderValue::from_str(backend_host) {
+            Ok(backend_host) => backend_host,
+            Err(_) => return,
+        };
+        self.backend_name = String::from(backend);
+        self.backend_host = backend_host;
+        self.log_tags.push((String::from("tenant"), host.clone()));
+        if let Some(profile) = tenant.get("profile").and_then(JsonValue::as_str) {
+            if let Some(apply) = self
+                .tenant_profiles
+                .iter()
+                .find(|tenant_profile| tenant_profile.name == profile)
+                .map(|tenant_profile| tenant_profile.apply)
+            {
+                let params = (self.download_param.clone(), self.filename_param.clone());
+                apply(self);
+                // Links to the tenant's own download parameters still work.
+                if params.0 != self.download_param || params.1 != self.filename_param {
+                    self.content_disposition =
+                        content_disposition(req, &self.download_param, &self.filename_param);
+                }
+            }
+        }
+    }
+
+    /// Switches to the first of `regional_backends` listing the client's
+    /// country or continent, so fragments come from the nearest replica.
+    fn select_regional_backend(&mut self, req: &Request) {
+        if self.regional_backends.is_empty() {
+            return;
+        }
+        let geo = match req.get_client_ip_addr().and_then(geo_lookup) {
+            Some(geo) => geo,
+            None => return,
+        };
+        let continent = geo.continent();
+        let codes = [geo.country_code(), continent.as_code()];
+        if let Some(regional) = self.regional_backends.iter().find(|regional| {
+            regional
+                .regions
+                .iter()
+                .any(|region| codes.iter().any(|code| region.eq_ignore_ascii_case(code)))
+        }) {
+            self.backend_name = regional.backend.clone();
+            self.backend_host = regional.host.clone();
+        }
+    }
+
+    /// Adds an origin query parameter whose value is an entry of a Secret
+    /// Store. Returns whether the entry was found.
+    pub fn add_origin_query_param_from_secret_store(
+        &mut self,
+        param: &str,
+        store: &str,
+        name: &str,
+    ) -> bool {
+        let value = SecretStore::open(store)
+            .ok()
+            .and_then(|store| store.get(name))
+            .and_then(|secret| String::from_utf8(secret.plaintext().to_vec()).ok());
+        match value {
+            Some(value) => {
+                self.origin_query_params.push((String::from(param), value));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Settings for running under Viceroy: the `local_origin` backend from
+    /// `fastly.toml`, and every record logged.
+    pub fn apply_local_profile(&mut self) {
+        self.backend_name = String::from("local_origin");
+        self.backend_host = HeaderValue::from_static("localhost");
+        self.log_endpoint = Some(String::from("sc_log"));
+        self.metrics_endpoint = Some(String::from("sc_metrics"));
+        self.log_fragments = true;
+        self.log_success_sample_rate = 1.0;
+    }
+
+    pub(crate) fn debug_enabled(&self, req: &Request) -> bool {
+        match (&self.debug_token, header_str(req.get_header("x-sc-debug"))) {
+            (Some(token), Some(value)) => token_matches(token, value),
+            _ => false,
+        }
+    }
+
+    #[cfg(feature = "chaos")]
+    pub(crate) fn chaos_enabled(&self, req: &Request) -> bool {
+        match (&self.chaos_token, header_str(req.get_header("x-sc-chaos"))) {
+            (Some(token), Some(value)) => token_matches(token, value),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn cors_origin(&self, req: &Request) -> Option<String> {
+        let origin = header_str(req.get_header("origin"))?;
+        if self
+            .cors_allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*")
+        {
+            Some(String::from("*"))
+        } else if self
+            .cors_allowed_origins
+            .iter()
+            .any(|allowed| allowed == origin)
+        {
+            Some(String::from(origin))
+        } else {
+            None
+        }
+    }
+
+    /// Whether the page embedding the object may do so, going by `Origin`
+    /// or else `Referer`. A `*.` prefix allows every subdomain.
+    pub(crate) fn hotlink_allowed(&self, req: &Request) -> bool {
+        if self.hotlink_allowed_hosts.is_empty() {
+            return true;
+        }
+        let url = match header_str(req.get_header("origin"))
+            .filter(|origin| *origin != "null")
+            .or_else(|| header_str(req.get_header("referer")))
+        {
+            Some(url) => url,
+            None => return self.hotlink_allow_missing,
+        };
+        let host = url
+            .split_once("://")
+            .map_or(url, |(_, rest)| rest)
+            .split(['/', '?', '#'])
+            .next()
+            .unwrap_or("");
+        let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+            _ => host,
+        };
+        let host = host.to_ascii_lowercase();
+        self.hotlink_allowed_hosts.iter().any(|allowed| {
+            let allowed = allowed.to_ascii_lowercase();
+            match allowed.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.ends_with('.')),
+                None => allowed == host,
+            }
+        })
+    }
+
+    pub(crate) fn only_if_cached(&self, req: &Request) -> bool {
+        let directive = req
+            .get_header_all("cache-control")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(","))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("only-if-cached"));
+        directive
+            || self
+                .only_if_cached_header
+                .as_deref()
+                .is_some_and(|name| req.get_header(name).is_some())
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn encrypted(&self) -> bool {
+        false
+    }
+
+    pub(crate) fn readahead_profile(&self, path: &str) -> Option<&ReadaheadProfile> {
+        let ext = path_extension(path)?;
+        self.readahead_profiles.iter().find(|profile| {
+            profile
+                .extensions
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(ext))
+        })
+    }
+
+    pub(crate) fn max_in_flight(&self) -> usize {
+        min(self.parallelism, self.backend_max_concurrency)
+    }
+
+    pub(crate) fn caching_enabled(&self, path: &str) -> bool {
+        !self
+            .uncached_prefixes
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    pub(crate) fn surrogate_control(&self, path: &str) -> Option<&str> {
+        self.fragment_surrogate_control
+            .iter()
+            .find(|(pattern, _)| wildcard_match(pattern, path))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub(crate) fn passthrough_enabled(&self, path: &str) -> bool {
+        self.passthrough_prefixes
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    pub(crate) fn live_enabled(&self, path: &str) -> bool {
+        self.live_prefixes
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    pub(crate) fn rewrite_path(&self, req: &mut Request) {
+        let path = req.get_path();
+        if let Some(path) = self.path_rewrites.iter().find_map(|(from, to)| {
+            path.strip_prefix(from.as_str())
+                .map(|rest| format!("{}{}", to, rest))
+        }) {
+            req.set_path(&path);
+        }
+    }
+
+    pub(crate) fn append_origin_query(&self, req: &mut Request) {
+        if self.origin_query_params.is_empty() {
+            return;
+        }
+        let mut query = req.get_query_str().map_or_else(String::new, String::from);
+        for (name, value) in &self.origin_query_params {
+            if !query.is_empty() {
+                query.push('&');
+            }
+            query.push_str(&format!("{}={}", name, value));
+        }
+        req.set_query_str(query);
+    }
+}
+
+/// Compares a token with a header value in time that depends only on their
+/// lengths, so that the token can't be guessed a byte at a time.
+fn token_matches(token: &str, value: &str) -> bool {
+    let (token, value) = (token.as_bytes(), value.as_bytes());
+    token.len() == value.len()
+        && token
+            .iter()
+            .zip(value)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+pub(crate) fn running_locally() -> bool {
+    // Viceroy reports localhost; deployed services get the cache node's name.
+    std::env::var("FASTLY_HOSTNAME").as_deref() == Ok("localhost")
+}
+
+pub(crate) fn path_extension(path: &str) -> Option<&str> {
+    path.rsplit_once("/")
+        .and_then(|(_, name)| name.rsplit_once("."))
+        .map(|(_, ext)| ext)
+}
+
+pub(crate) fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let (pattern, value) = (pattern.as_bytes(), value.as_bytes());
+    let (mut p, mut v) = (0, 0);
+    // Where the last `*` was, and how much of the value it has taken.
+    let mut star = None;
+    while v < value.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, v));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == value[v] {
+            p += 1;
+            v += 1;
+        } else if let Some((star_p, star_v)) = star {
+            p = star_p + 1;
+            v = star_v + 1;
+            star = Some((star_p, star_v + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+pub(crate) fn content_disposition(
+    req: &Request,
+    download_param: &str,
+    filename_param: &str,
+) -> Option<String> {
+    let mut download = false;
+    let mut filename = None;
+    for pair in req.get_query_str()?.split("&") {
+        let (name, value) = pair.split_once("=").unwrap_or((pair, ""));
+        if name == download_param {
+            download = value != "0";
+        } else if name == filename_param
+            && !value.is_empty()
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$%&+-.^_`|~".contains(c))
+        {
+            // Still percent-encoded, which is what the ext-value syntax wants.
+            filename = Some(value);
+        }
+    }
+    match (download, filename) {
+        (_, Some(filename)) => Some(format!("attachment; filename*=UTF-8''{}", filename)),
+        (true, None) => Some(String::from("attachment")),
+        (false, None) => None,
+    }
+}
+
+pub(crate) fn strip_query_params(req: &mut Request, names: &[&str]) {
+    let query = match req.get_query_str() {
+        Some(query) => query
+            .split("&")
+            .filter(|pair| !names.contains(&pair.split_once("=").map_or(*pair, |(name, _)| name)))
+            .collect::<Vec<_>>()
+            .join("&"),
+        None => return,
+    };
+    if query.is_empty() {
+        req.remove_query();
+    } else {
+        req.set_query_str(query);
+    }
+}
+
+pub(crate) const CONTENT_TYPES: [(&str, &str); 11] = [
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("m4s", "video/iso.segment"),
+    ("m4a", "audio/mp4"),
+    ("webm", "video/webm"),
+    ("ts", "video/mp2t"),
+    ("mp3", "audio/mpeg"),
+    ("m3u8", "application/vnd.apple.mpegurl"),
+    ("mpd", "application/dash+xml"),
+    ("zip", "application/zip"),
+    ("pdf", "application/pdf"),
+];
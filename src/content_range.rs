@@ -0,0 +1,265 @@
+use crate::config::Config;
+use crate::error::SegmentedCacheError as Error;
+use crate::headers::header_str;
+use crate::range::parse_digits;
+use fastly::http::StatusCode;
+use fastly::Response;
+
+pub(crate) struct ContentRange {
+    pub(crate) first: usize,
+    pub(crate) last: usize,
+    pub(crate) complete_length: usize,
+}
+
+impl ContentRange {
+    pub(crate) fn new(
+        resp: &Response,
+        known_length: Option<usize>,
+        config: &Config,
+    ) -> Result<Self, Error> {
+        let values = resp.get_header_all("content-range").collect::<Vec<_>>();
+        let value = match &values[..] {
+            [] => return Err(Error::ContentRangeParse("missing content-range")),
+            [value] => value
+                .to_str()
+                .map_err(|_| Error::ContentRangeParse("invalid content-range header value"))?,
+            [_, _, ..] => return Err(Error::ContentRangeParse("multiple content-range fields")),
+        };
+        Self::parse(value, known_length, config.lenient_content_range)
+            .map_err(Error::ContentRangeParse)
+    }
+
+    /// Parses a `Content-Range` value, taking `known_length` for a `*`
+    /// complete length.
+    pub(crate) fn parse(
+        value: &str,
+        known_length: Option<usize>,
+        lenient: bool,
+    ) -> Result<Self, &'static str> {
+        let normalized;
+        let value = if lenient {
+            normalized = Self::normalize(value);
+            normalized.as_str()
+        } else {
+            value
+        };
+        let field = match value.split_once(" ") {
+            Some(("bytes", range)) => range,
+            _ => return Err("content-range not bytes"),
+        };
+        let (range, complete_length) = match field.split_once("/") {
+            Some((range, "*")) => match known_length {
+                Some(known_length) => (range, known_length),
+                None => return Err("unknown complete length in content-range not supported"),
+            },
+            Some(("*", _)) => return Err("unsatisfied range in content-range not supported"),
+            Some((range, complete_length)) => (
+                range,
+                parse_digits(complete_length).ok_or("invalid content-range complete length")?,
+            ),
+            _ => return Err("cannot parse content-range"),
+        };
+        if complete_length == 0 {
+            return Err("zero complete length in content-range");
+        }
+        let content_range = match range.split_once("-") {
+            Some((first, last)) => {
+                let first = parse_digits(first).ok_or("invalid content-range lower bound")?;
+                let last = parse_digits(last).ok_or("invalid content-range upper bound")?;
+                if last < first {
+                    return Err("content-range upper bound lower than lower bound");
+                }
+                if first >= complete_length {
+                    return Err("content-range lower bound not lower than complete length");
+                }
+                if last >= complete_length {
+                    return Err("content-range upper bound not lower than complete length");
+                }
+                ContentRange {
+                    first,
+                    last,
+                    complete_length,
+                }
+            }
+            _ => return Err("cannot parse range in content-range"),
+        };
+        Ok(content_range)
+    }
+
+    pub(crate) fn normalize(value: &str) -> String {
+        // Tolerates `bytes=0-99/200`, any case of the unit and stray whitespace.
+        let value = value.trim();
+        match value.find([' ', '\t', '=']) {
+            Some(i) => {
+                let rest = value[i + 1..]
+                    .chars()
+                    .filter(|c| !c.is_whitespace())
+                    .collect::<String>();
+                format!("{} {}", value[..i].to_ascii_lowercase(), rest)
+            }
+            None => value.to_string(),
+        }
+    }
+
+    pub(crate) fn has_unknown_length(resp: &Response) -> bool {
+        header_str(resp.get_header("content-range"))
+            .is_some_and(|value| value.trim_end().ends_with("/*"))
+    }
+
+    pub(crate) fn unsatisfied_length(resp: &Response) -> Option<usize> {
+        Self::parse_unsatisfied(header_str(resp.get_header("content-range"))?)
+    }
+
+    /// The complete length in an unsatisfied `bytes */length` value.
+    pub(crate) fn parse_unsatisfied(value: &str) -> Option<usize> {
+        parse_digits(value.trim().strip_prefix("bytes */")?)
+    }
+
+    pub(crate) fn is_empty_object(resp: &Response) -> bool {
+        match resp.get_status() {
+            StatusCode::RANGE_NOT_SATISFIABLE => Self::unsatisfied_length(resp) == Some(0),
+            _ => header_str(resp.get_header("content-length"))
+                .is_some_and(|value| value.trim() == "0"),
+        }
+    }
+
+    pub(crate) fn from_full_body(resp: &Response) -> Option<Self> {
+        let complete_length = header_str(resp.get_header("content-length"))?
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|&length| length > 0)?;
+        Some(ContentRange {
+            first: 0,
+            last: complete_length - 1,
+            complete_length,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// A valid range of an object of up to a TiB.
+    fn bounds() -> impl Strategy<Value = (usize, usize, usize)> {
+        (1..1usize << 40)
+            .prop_flat_map(|complete_length| (0..complete_length, Just(complete_length)))
+            .prop_flat_map(|(first, complete_length)| {
+                (Just(first), first..complete_length, Just(complete_length))
+            })
+    }
+
+    fn fields(
+        value: &str,
+        known_length: Option<usize>,
+        lenient: bool,
+    ) -> Option<(usize, usize, usize)> {
+        ContentRange::parse(value, known_length, lenient)
+            .ok()
+            .map(|range| (range.first, range.last, range.complete_length))
+    }
+
+    proptest! {
+        #[test]
+        fn parse_round_trips((first, last, complete_length) in bounds(), lenient in any::<bool>()) {
+            let value = format!("bytes {}-{}/{}", first, last, complete_length);
+            prop_assert_eq!(fields(&value, None, lenient), Some((first, last, complete_length)));
+            // A length the origin did state wins over the known one.
+            prop_assert_eq!(
+                fields(&value, Some(complete_length + 1), lenient),
+                Some((first, last, complete_length))
+            );
+        }
+
+        #[test]
+        fn parse_takes_known_length_for_star((first, last, complete_length) in bounds()) {
+            let value = format!("bytes {}-{}/*", first, last);
+            prop_assert_eq!(
+                fields(&value, Some(complete_length), false),
+                Some((first, last, complete_length))
+            );
+            prop_assert!(ContentRange::parse(&value, None, false).is_err());
+        }
+
+        #[test]
+        fn parse_rejects_out_of_order_bounds((first, last, complete_length) in bounds()) {
+            prop_assume!(first < last);
+            let value = format!("bytes {}-{}/{}", last, first, complete_length);
+            prop_assert!(ContentRange::parse(&value, None, false).is_err());
+        }
+
+        #[test]
+        fn parse_rejects_bounds_past_the_end((first, last, complete_length) in bounds(), over in 0..1usize << 20) {
+            let past = complete_length + over;
+            let value = format!("bytes {}-{}/{}", first, past, complete_length);
+            prop_assert!(ContentRange::parse(&value, None, false).is_err());
+            let value = format!("bytes {}-{}/{}", past, past + last - first, complete_length);
+            prop_assert!(ContentRange::parse(&value, None, false).is_err());
+        }
+
+        #[test]
+        fn parse_rejects_overflow((first, last, _) in bounds(), excess in 1..u64::MAX as u128) {
+            let huge = (usize::MAX as u128 + excess).to_string();
+            for value in [
+                format!("bytes {}-{}/{}", first, last, huge),
+                format!("bytes {}-{}/{}", first, huge, huge),
+                format!("bytes {}-{}/{}", huge, huge, huge),
+            ] {
+                prop_assert!(ContentRange::parse(&value, Some(usize::MAX), true).is_err());
+            }
+        }
+
+        #[test]
+        fn parse_rejects_unsatisfied_ranges(complete_length in any::<usize>()) {
+            let value = format!("bytes */{}", complete_length);
+            prop_assert!(ContentRange::parse(&value, None, true).is_err());
+            prop_assert_eq!(ContentRange::parse_unsatisfied(&value), Some(complete_length));
+            let padded = format!("  {}\t", value);
+            prop_assert_eq!(ContentRange::parse_unsatisfied(&padded), Some(complete_length));
+        }
+
+        #[test]
+        fn parse_unsatisfied_needs_a_star((first, last, complete_length) in bounds()) {
+            let value = format!("bytes {}-{}/{}", first, last, complete_length);
+            prop_assert_eq!(ContentRange::parse_unsatisfied(&value), None);
+            prop_assert_eq!(ContentRange::parse_unsatisfied("bytes */*"), None);
+        }
+
+        #[test]
+        fn lenient_parse_tolerates_sloppy_values(
+            (first, last, complete_length) in bounds(),
+            unit in "[bB][yY][tT][eE][sS]",
+            separator in "[ \t=]",
+            space in "[ \t]{0,2}",
+        ) {
+            let value = format!(
+                "{space}{unit}{separator}{space}{first}{space}-{last}/{space}{complete_length}{space}",
+                space = space,
+                unit = unit,
+                separator = separator,
+                first = first,
+                last = last,
+                complete_length = complete_length,
+            );
+            prop_assert_eq!(fields(&value, None, true), Some((first, last, complete_length)));
+            if value != format!("bytes {}-{}/{}", first, last, complete_length) {
+                prop_assert!(ContentRange::parse(&value, None, false).is_err());
+            }
+        }
+
+        #[test]
+        fn normalize_is_idempotent(unit in "[a-zA-Z]{1,8}", separator in "[ \t=]", rest in "[0-9*/=-][0-9 \t*/=-]{0,15}") {
+            let once = ContentRange::normalize(&format!("{}{}{}", unit, separator, rest));
+            prop_assert_eq!(ContentRange::normalize(&once), once.clone());
+            prop_assert!(once.starts_with(&format!("{} ", unit.to_ascii_lowercase())));
+        }
+    }
+
+    #[test]
+    fn parse_rejects_zero_complete_length() {
+        assert!(ContentRange::parse("bytes 0-0/0", None, false).is_err());
+        assert!(ContentRange::parse("bytes 0-0/*", Some(0), false).is_err());
+    }
+}
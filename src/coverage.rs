@@ -0,0 +1,155 @@
+use crate::budget::Permit;
+use crate::config::Config;
+use crate::content_range::ContentRange;
+use crate::error::SegmentedCacheError as Error;
+use crate::fetch::fragment_request;
+use crate::headers::cache_hit;
+use crate::kv::Manifest;
+use crate::log::JsonRecord;
+use crate::prewarm::object_request;
+use crate::range::parse_digits;
+use crate::trace::TraceContext;
+use fastly::http::request::PendingRequest;
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use std::cmp::min;
+
+pub(crate) const COVERAGE_PATH: &str = "/__sc/coverage";
+
+/// Reports which blocks of `url` the caches in front of the origin hold,
+/// probing each with an `only-if-cached` `HEAD`, so nothing is fetched from
+/// the origin. The length comes from the `length` parameter, the KV Store
+/// manifest or the first block, whichever is known first.
+pub(crate) fn coverage_response(req: &Request, config: &Config, trace: &TraceContext) -> Response {
+    let url = match req.get_query_parameter("url") {
+        Some(url) => url,
+        None => return json_error(StatusCode::BAD_REQUEST, "missing url"),
+    };
+    let length = match req.get_query_parameter("length") {
+        Some(length) => match parse_digits(length) {
+            Some(length) => Some(length),
+            None => return json_error(StatusCode::BAD_REQUEST, "invalid length"),
+        },
+        None => None,
+    };
+    let object = object_request(req, url);
+    match coverage(url, &object, config, trace, length) {
+        Ok(Some(report)) => Response::from_status(StatusCode::OK)
+            .with_header("content-type", "application/json")
+            .with_header("cache-control", "no-store")
+            .with_body(report),
+        Ok(None) => json_error(
+            StatusCode::NOT_FOUND,
+            "length unknown and first block not cached",
+        ),
+        Err(e) => json_error(e.status(), &e.to_string()),
+    }
+}
+
+fn coverage(
+    url: &str,
+    object: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    length: Option<usize>,
+) -> Result<Option<String>, Error> {
+    let block_size = config.block_size;
+    let length = length.or_else(|| Manifest::lookup(object, config).map(|m| m.complete_length));
+    let mut cached = Vec::new();
+    let complete_length = match length {
+        Some(length) => length,
+        None => {
+            let beresp = probe(object, config, trace, 0, block_size - 1)?
+                .wait()
+                .map_err(|e| Error::send("cache probe wait", e))?;
+            if !is_cached(&beresp) {
+                return Ok(None);
+            }
+            cached.push(true);
+            ContentRange::new(&beresp, None, config)?.complete_length
+        }
+    };
+    let blocks = min(complete_length.div_ceil(block_size), config.max_fragments);
+    while cached.len() < blocks {
+        let start = cached.len();
+        let pending = (start..min(blocks, start + config.max_in_flight()))
+            .map_while(|block| Permit::try_acquire(config).map(|permit| (block, permit)))
+            .map(|(block, permit)| {
+                let first = block * block_size;
+                let last = min(first + block_size, complete_length) - 1;
+                probe(object, config, trace, first, last).map(|promise| (promise, permit))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if pending.is_empty() {
+            return Err(Error::Inconsistency(String::from(
+                "backend concurrency budget spent",
+            )));
+        }
+        for (promise, _permit) in pending {
+            let beresp = promise
+                .wait()
+                .map_err(|e| Error::send("cache probe wait", e))?;
+            cached.push(is_cached(&beresp));
+        }
+    }
+
+    // Runs of blocks that aren't cached, as the ranges a prewarm would need.
+    let mut missing = Vec::new();
+    let mut block = 0;
+    while block < cached.len() {
+        if cached[block] {
+            block += 1;
+            continue;
+        }
+        let first = block;
+        while block < cached.len() && !cached[block] {
+            block += 1;
+        }
+        let last = min(block * block_size, complete_length) - 1;
+        missing.push(format!("\"bytes={}-{}\"", first * block_size, last));
+    }
+    let map: String = cached
+        .iter()
+        .map(|&hit| if hit { '#' } else { '.' })
+        .collect();
+    Ok(Some(
+        JsonRecord::new()
+            .str("url", url)
+            .num("length", complete_length)
+            .num("block_size", block_size)
+            .num("blocks", cached.len())
+            .num(
+                "truncated",
+                cached.len() < complete_length.div_ceil(block_size),
+            )
+            .num("cached", cached.iter().filter(|&&hit| hit).count())
+            .str("map", &map)
+            .raw("missing", &format!("[{}]", missing.join(",")))
+            .finish(),
+    ))
+}
+
+fn probe(
+    object: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    first: usize,
+    last: usize,
+) -> Result<PendingRequest, Error> {
+    let range = format!("bytes={}-{}", first, last);
+    let (mut bereq, _) = fragment_request(object, config, trace, Method::HEAD, &range);
+    bereq.set_header("cache-control", "only-if-cached");
+    bereq
+        .send_async(&config.backend_name)
+        .map_err(|e| Error::send("cache probe send_async", e))
+}
+
+fn is_cached(beresp: &Response) -> bool {
+    beresp.get_status().is_success() && cache_hit(beresp) != Some(false)
+}
+
+fn json_error(status: StatusCode, error: &str) -> Response {
+    Response::from_status(status)
+        .with_header("content-type", "application/json")
+        .with_body(JsonRecord::new().str("error", error).finish())
+}
@@ -0,0 +1,159 @@
+use crate::archive::ArchiveReader;
+use crate::config::Config;
+use crate::error::SegmentedCacheError as Error;
+use crate::fetch::origin_request;
+use crate::kv::{lookup_decompressed_length, save_decompressed_length};
+use crate::log::with_log;
+use crate::range::{RequestRange, ResolvedRange};
+use crate::response::{range_not_satisfiable, set_range_headers, set_response_headers};
+use crate::trace::TraceContext;
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+use flate2::read::MultiGzDecoder;
+use std::cmp::min;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+/// Serves ranges of the decompressed bytes of an object the origin only
+/// stores gzip-compressed. The length comes from the gzip trailer, and a
+/// range is decompressed from the start of the object, whose blocks the
+/// caches in front of the origin keep compressed.
+pub(crate) fn decompressed_response(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    header_only: bool,
+    resp_header_sent: &mut bool,
+    resp_headers: &[(String, String)],
+) -> Result<Option<Response>, Error> {
+    let mut reader = ArchiveReader::open(req, config, trace)?;
+    // The origin's compressed bytes are then all the client gets.
+    if reader.no_transform {
+        let (bereq, _) = origin_request(req, config, trace, req.get_method().clone());
+        return bereq
+            .send(&config.backend_name)
+            .map(Some)
+            .map_err(|e| Error::send("no-transform backend request send", e));
+    }
+    reader.encoded = true;
+    if reader.complete_length < 18 {
+        return Err(Error::Inconsistency(String::from(
+            "object too short for gzip",
+        )));
+    }
+    // The trailer's ISIZE is only the last member's length modulo 2^32, so
+    // the length is found by decompressing the object once and kept with
+    // the object's manifest.
+    let complete_length = match lookup_decompressed_length(req, config, &reader.validators) {
+        Some(length) => length,
+        None => {
+            let mut decoder = MultiGzDecoder::new(CompressedBody {
+                reader: &mut reader,
+                position: 0,
+            });
+            let length = io::copy(&mut decoder, &mut io::sink()).map_err(Error::Read)?;
+            let length = usize::try_from(length)
+                .map_err(|_| Error::Inconsistency(String::from("decompressed object too large")))?;
+            save_decompressed_length(req, config, &reader.validators, length);
+            // The range is decompressed afresh, with a budget of its own.
+            reader.fetched = 0;
+            length
+        }
+    };
+
+    let mut resp = Response::from_status(StatusCode::OK);
+    let content_type = reader
+        .content_type
+        .as_deref()
+        .filter(|value| !value.contains("gzip"))
+        .unwrap_or("application/octet-stream");
+    resp.set_header("content-type", content_type);
+    // The decompressed bytes are another representation of the object.
+    if let Some(etag) = &reader.validators.etag {
+        let etag = etag.trim_start_matches("W/");
+        resp.set_header("etag", format!("W/{}", etag));
+    }
+    if let Some(last_modified) = &reader.validators.last_modified {
+        resp.set_header("last-modified", last_modified);
+    }
+    if complete_length == 0 {
+        resp.set_header("content-length", "0");
+        return Ok(Some(resp));
+    }
+    let req_range = RequestRange::new(req, config)?;
+    let range = match ResolvedRange::new(&req_range, complete_length) {
+        Some(range) => range,
+        None => return Ok(Some(range_not_satisfiable(complete_length))),
+    };
+    set_range_headers(&mut resp, &req_range, &range, complete_length, config);
+    if header_only {
+        return Ok(Some(resp));
+    }
+    resp.set_header("x-request-id", with_log(|log| log.request_id.clone()));
+    set_response_headers(&mut resp, resp_headers);
+    config.hooks.on_response_headers(&mut resp);
+    with_log(|log| log.status = Some(resp.get_status()));
+    let mut resp_body = resp.stream_to_client();
+    *resp_header_sent = true;
+
+    let mut decoder = MultiGzDecoder::new(CompressedBody {
+        reader: &mut reader,
+        position: 0,
+    });
+    let mut buf = vec![0; config.read_chunk_size];
+    let mut position = 0;
+    while position <= range.last {
+        let len = decoder.read(&mut buf).map_err(Error::Read)?;
+        if len == 0 {
+            return Err(Error::Truncated { offset: position });
+        }
+        let end = position + len;
+        if end > range.first {
+            let start = range.first.saturating_sub(position);
+            let stop = min(len, range.last + 1 - position);
+            resp_body
+                .write_all(&buf[start..stop])
+                .map_err(|_| Error::ClientGone)?;
+            with_log(|log| log.bytes_sent = position + stop - range.first);
+        } else {
+            with_log(|log| log.discarded_bytes = end);
+        }
+        position = end;
+    }
+    let _ = resp_body.finish();
+    Ok(None)
+}
+
+/// The compressed object as a stream, read a block at a time with the
+/// next blocks in flight.
+struct CompressedBody<'r, 'a> {
+    reader: &'r mut ArchiveReader<'a>,
+    position: usize,
+}
+
+impl Read for CompressedBody<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let block_size = self.reader.config.block_size;
+        let remaining = self.reader.complete_length - self.position;
+        if remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let block_first = self.position / block_size * block_size;
+        let ahead: Vec<usize> = (1..=self.reader.config.parallelism)
+            .map(|n| block_first + n * block_size)
+            .filter(|&first| first < self.reader.complete_length)
+            .collect();
+        let len = min(
+            min(buf.len(), remaining),
+            block_first + block_size - self.position,
+        );
+        let bytes = self
+            .reader
+            .prefetch(&ahead)
+            .and_then(|()| self.reader.read(self.position, len))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        buf[..len].copy_from_slice(&bytes);
+        self.position += len;
+        Ok(len)
+    }
+}
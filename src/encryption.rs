@@ -0,0 +1,96 @@
+use crate::config::Config;
+use crate::error::SegmentedCacheError as Error;
+use crate::fetch::origin_request;
+use crate::headers::header_str;
+use crate::trace::TraceContext;
+use aes::{Aes128, Aes256};
+use ctr::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use ctr::Ctr128BE;
+use fastly::http::Method;
+use fastly::secret_store::SecretStore;
+use fastly::{Request, Response};
+
+/// Objects stored encrypted with AES in counter mode. Counter mode keeps
+/// every byte at its offset, so a plaintext range is fetched as the same
+/// ciphertext range and decrypted on the way to the client. The caches and
+/// the KV Store only ever hold ciphertext.
+pub struct Encryption {
+    key: Vec<u8>,
+    /// The origin response header carrying the object's initial counter
+    /// block, hex-encoded.
+    pub iv_header: String,
+}
+
+impl Encryption {
+    /// Reads a 128-bit or 256-bit key from an entry of a Secret Store.
+    pub fn from_secret_store(store: &str, name: &str) -> Option<Self> {
+        let key = SecretStore::open(store)
+            .ok()?
+            .get(name)?
+            .plaintext()
+            .to_vec();
+        if key.len() != 16 && key.len() != 32 {
+            return None;
+        }
+        Some(Encryption {
+            key,
+            iv_header: String::from("x-amz-meta-iv"),
+        })
+    }
+
+    /// A decryptor for the object `beresp` is a fragment of. Blocks served
+    /// from the KV Store carry no origin headers, so the initial counter
+    /// block then comes from a `HEAD` request.
+    pub(crate) fn decryptor(
+        &self,
+        req: &Request,
+        config: &Config,
+        trace: &TraceContext,
+        beresp: &Response,
+    ) -> Result<Decryptor, Error> {
+        let iv = match header_str(beresp.get_header(&self.iv_header)) {
+            Some(iv) => String::from(iv),
+            None => {
+                let (bereq, _) = origin_request(req, config, trace, Method::HEAD);
+                let head = bereq
+                    .send(&config.backend_name)
+                    .map_err(|e| Error::send("initialization vector request send", e))?;
+                header_str(head.get_header(&self.iv_header))
+                    .map(String::from)
+                    .ok_or(Error::Decryption("missing initialization vector"))?
+            }
+        };
+        let iv = Some(iv.trim())
+            .filter(|iv| iv.len() == 32 && iv.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|iv| u128::from_str_radix(iv, 16).ok())
+            .ok_or(Error::Decryption("invalid initialization vector"))?
+            .to_be_bytes();
+        let cipher = if self.key.len() == 16 {
+            Ctr128BE::new_from_slices(&self.key, &iv).map(Decryptor::Aes128)
+        } else {
+            Ctr128BE::new_from_slices(&self.key, &iv).map(Decryptor::Aes256)
+        };
+        cipher.map_err(|_| Error::Decryption("invalid key"))
+    }
+}
+
+pub(crate) enum Decryptor {
+    Aes128(Ctr128BE<Aes128>),
+    Aes256(Ctr128BE<Aes256>),
+}
+
+impl Decryptor {
+    /// Decrypts `buf` in place, as the bytes of the object from `offset` on.
+    pub(crate) fn apply(&mut self, offset: usize, buf: &mut [u8]) {
+        match self {
+            Decryptor::Aes128(cipher) => {
+                cipher.seek(offset as u64);
+                cipher.apply_keystream(buf);
+            }
+            Decryptor::Aes256(cipher) => {
+                cipher.seek(offset as u64);
+                cipher.apply_keystream(buf);
+            }
+        }
+    }
+}
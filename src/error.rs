@@ -0,0 +1,134 @@
+use crate::log::with_log;
+use fastly::http::request::{SendError, SendErrorCause};
+use fastly::http::StatusCode;
+use fastly::Response;
+use std::fmt::Display;
+
+/// Everything that can go wrong while serving a segmented request.
+#[derive(Debug)]
+pub enum SegmentedCacheError {
+    /// The client's `Range` header could not be parsed.
+    RangeParse(&'static str),
+    /// A backend response had a missing or malformed `Content-Range`.
+    ContentRangeParse(&'static str),
+    /// A fragment request was answered with something other than 206.
+    OriginStatus(StatusCode),
+    /// A fragment was sent with a content encoding other than identity.
+    Encoding(String),
+    /// Fragments disagree with each other or with the ranges requested.
+    Inconsistency(String),
+    /// A fragment became unsatisfiable because the object got shorter.
+    ObjectShrank(String),
+    /// The list of objects to prewarm could not be parsed.
+    Manifest(&'static str),
+    /// An encrypted object could not be decrypted.
+    Decryption(&'static str),
+    /// The object's bytes don't have the digest they were expected to.
+    Integrity(String),
+    /// A fragment body ended before its content range did.
+    Truncated { offset: usize },
+    /// A backend request timed out.
+    Timeout { context: String, source: SendError },
+    /// A backend request failed for another reason.
+    Send { context: String, source: SendError },
+    /// A fragment body could not be read.
+    Read(std::io::Error),
+    /// The client stopped accepting the response body.
+    ClientGone,
+    /// An error together with what was being done when it happened.
+    Context {
+        context: String,
+        source: Box<SegmentedCacheError>,
+    },
+}
+
+impl SegmentedCacheError {
+    pub(crate) fn send(context: impl Into<String>, source: SendError) -> Self {
+        let context = context.into();
+        match source.root_cause() {
+            SendErrorCause::DnsTimeout
+            | SendErrorCause::ConnectionTimeout
+            | SendErrorCause::HttpResponseTimeout => {
+                SegmentedCacheError::Timeout { context, source }
+            }
+            _ => SegmentedCacheError::Send { context, source },
+        }
+    }
+
+    pub(crate) fn context(self, context: impl Into<String>) -> Self {
+        SegmentedCacheError::Context {
+            context: context.into(),
+            source: Box::new(self),
+        }
+    }
+
+    /// The status to answer with when nothing was sent to the client yet.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            SegmentedCacheError::Context { source, .. } => source.status(),
+            SegmentedCacheError::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            SegmentedCacheError::RangeParse(_) => StatusCode::BAD_REQUEST,
+            SegmentedCacheError::ClientGone => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl Display for SegmentedCacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SegmentedCacheError::RangeParse(what)
+            | SegmentedCacheError::ContentRangeParse(what) => f.write_str(what),
+            SegmentedCacheError::OriginStatus(status) => {
+                write!(f, "fragment status code {} rather than 206", status)
+            }
+            SegmentedCacheError::Encoding(encoding) => {
+                write!(f, "fragment has content-encoding {}", encoding)
+            }
+            SegmentedCacheError::Inconsistency(what) => f.write_str(what),
+            SegmentedCacheError::Manifest(what) => write!(f, "prewarm manifest: {}", what),
+            SegmentedCacheError::Decryption(what) => write!(f, "decryption: {}", what),
+            SegmentedCacheError::Integrity(what) => write!(f, "integrity: {}", what),
+            SegmentedCacheError::ObjectShrank(what) => {
+                write!(f, "object shrank during transfer: {}", what)
+            }
+            SegmentedCacheError::Truncated { offset } => {
+                write!(f, "truncated fragment at offset {}", offset)
+            }
+            SegmentedCacheError::Timeout { context, source }
+            | SegmentedCacheError::Send { context, source } => write!(f, "{}: {}", context, source),
+            SegmentedCacheError::Read(e) => write!(f, "reading fragment: {}", e),
+            SegmentedCacheError::ClientGone => f.write_str("client went away"),
+            SegmentedCacheError::Context { context, source } => {
+                write!(f, "{}: {}", context, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SegmentedCacheError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SegmentedCacheError::Timeout { source, .. }
+            | SegmentedCacheError::Send { source, .. } => Some(source),
+            SegmentedCacheError::Read(e) => Some(e),
+            SegmentedCacheError::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+pub fn error_response(e: &SegmentedCacheError) -> Response {
+    let request_id = with_log(|log| log.request_id.clone());
+    Response::from_status(e.status())
+        .with_header("x-request-id", request_id)
+        .with_body_text_plain(&format!("{}\n", e))
+}
+
+pub(crate) fn error_class(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_GATEWAY => "origin",
+        StatusCode::GATEWAY_TIMEOUT => "origin_timeout",
+        _ => "internal",
+    }
+}
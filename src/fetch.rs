@@ -0,0 +1,564 @@
+use crate::budget::Permit;
+use crate::config::Config;
+use crate::content_range::ContentRange;
+use crate::error::SegmentedCacheError as Error;
+use crate::headers::{cache_hit, header_str};
+use crate::kv::{surrogate_key, FragmentStore};
+use crate::log::with_log;
+use crate::stream::Fragment;
+use crate::trace::TraceContext;
+use fastly::http::request::PendingRequest;
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+pub(crate) struct FragReqGen {
+    pub(crate) position: usize,
+    pub(crate) last: usize,
+    pub(crate) block_size: usize,
+}
+
+impl FragReqGen {
+    pub(crate) fn new(position: usize, last: usize, config: &Config) -> Self {
+        FragReqGen {
+            position,
+            last,
+            block_size: config.block_size,
+        }
+    }
+
+    /// Cuts the blocks at multiples of `block_size` rather than of
+    /// `Config::block_size`. A `position` between two cuts first fetches up
+    /// to the next one.
+    pub(crate) fn with_block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub(crate) fn stop(&mut self) {
+        self.position = self.last + 1;
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        if self.position <= self.last {
+            self.last / self.block_size - self.position / self.block_size + 1
+        } else {
+            0
+        }
+    }
+}
+
+impl Iterator for FragReqGen {
+    type Item = String;
+    fn next(&mut self) -> Option<String> {
+        if self.position <= self.last {
+            let pos = self.position;
+            self.position = (pos / self.block_size + 1) * self.block_size;
+            Some(format!("bytes={}-{}", pos, self.position - 1))
+        } else {
+            None
+        }
+    }
+}
+
+/// The block size for an object stored in parts of a multipart upload, so
+/// that no block spans two parts and each is served by S3 from a single one.
+/// The part size is `Config::origin_part_size`, or is inferred from an ETag
+/// of the form `"<md5>-<parts>"` when `Config::detect_part_size` is set.
+pub(crate) fn part_block_size(
+    config: &Config,
+    etag: Option<&str>,
+    complete_length: usize,
+) -> usize {
+    let part_size = config.origin_part_size.or_else(|| {
+        let parts = etag
+            .filter(|_| config.detect_part_size)?
+            .trim_start_matches("W/")
+            .trim_matches('"')
+            .rsplit_once('-')?
+            .1
+            .parse::<usize>()
+            .ok()
+            .filter(|&parts| parts > 1)?;
+        // Upload tools pick a whole number of MiB for every part but the
+        // last, which is no larger. When several sizes fit, as with few
+        // parts, the smallest is taken.
+        let part_size = complete_length.div_ceil(parts).div_ceil(1 << 20) << 20;
+        ((parts - 1) * part_size < complete_length).then_some(part_size)
+    });
+    let block_size = config.block_size;
+    match part_size {
+        Some(part_size) if !part_size.is_multiple_of(block_size) => {
+            // The largest block size under `Config::block_size` dividing
+            // the part, unless it would be much smaller.
+            let cuts = part_size.div_ceil(block_size);
+            (cuts..=4 * cuts)
+                .find(|&cuts| part_size.is_multiple_of(cuts))
+                .map_or(block_size, |cuts| part_size / cuts)
+        }
+        _ => block_size,
+    }
+}
+
+/// The request header naming the content digest of the object a fragment
+/// belongs to. The caches in front of the origin key on it when present.
+pub(crate) const CONTENT_DIGEST_HEADER: &str = "x-sc-content-digest";
+
+/// The digest of the object's bytes from `Config::content_digest_header`,
+/// when it is one the caches can key on.
+pub(crate) fn content_digest(config: &Config, beresp: &Response) -> Option<String> {
+    let digest = header_str(beresp.get_header(config.content_digest_header.as_deref()?))?.trim();
+    (!digest.is_empty() && digest.len() <= 128 && digest.bytes().all(|b| b.is_ascii_graphic()))
+        .then(|| String::from(digest))
+}
+
+/// The request headers carrying `Config::fragment_ttl_max` and
+/// `Config::fragment_ttl_min` to the VCL service.
+pub(crate) const TTL_MAX_HEADER: &str = "x-sc-ttl-max";
+pub(crate) const TTL_MIN_HEADER: &str = "x-sc-ttl-min";
+/// The request header carrying `Config::fragment_surrogate_control`.
+pub(crate) const SURROGATE_CONTROL_HEADER: &str = "x-sc-surrogate-control";
+/// The request header carrying the surrogate key of the object a fragment
+/// belongs to, for the VCL service to tag the block with.
+pub(crate) const SURROGATE_KEY_HEADER: &str = "x-sc-surrogate-key";
+
+pub(crate) struct FetchInfo {
+    pub(crate) range: String,
+    pub(crate) span_id: String,
+    pub(crate) duration: Duration,
+}
+
+pub(crate) const CONDITIONAL_HEADERS: [&str; 5] = [
+    "if-match",
+    "if-modified-since",
+    "if-none-match",
+    "if-range",
+    "if-unmodified-since",
+];
+
+pub(crate) fn origin_request(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    method: Method,
+) -> (Request, String) {
+    build_origin_request(req, config, trace, method, None)
+}
+
+fn build_origin_request(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    method: Method,
+    range: Option<&str>,
+) -> (Request, String) {
+    let (span_id, traceparent) = trace.child();
+    let mut bereq = req.clone_without_body();
+    bereq.set_method(method);
+    bereq.set_pass(true);
+    match range {
+        Some(range) => {
+            bereq.set_header("range", range);
+            if let Some(max) = config.fragment_ttl_max {
+                bereq.set_header(TTL_MAX_HEADER, format!("{}s", max.as_secs()));
+            }
+            if let Some(min) = config.fragment_ttl_min {
+                bereq.set_header(TTL_MIN_HEADER, format!("{}s", min.as_secs()));
+            }
+            if let Some(value) = config.surrogate_control(req.get_path()) {
+                bereq.set_header(SURROGATE_CONTROL_HEADER, value);
+            }
+            bereq.set_header(SURROGATE_KEY_HEADER, surrogate_key(req, config));
+        }
+        None => {
+            bereq.remove_header("range");
+        }
+    }
+    // A 304 or 412 partway through the stream can't be turned into body bytes.
+    for name in CONDITIONAL_HEADERS {
+        bereq.remove_header(name);
+    }
+    bereq.remove_header("x-sc-chaos");
+    // Byte ranges of an encoded representation can't be stitched together.
+    bereq.set_header("accept-encoding", "identity");
+    bereq.set_header("host", &config.backend_host);
+    config.rewrite_path(&mut bereq);
+    config.append_origin_query(&mut bereq);
+    bereq.set_header("traceparent", traceparent);
+    if let Some(via) = &config.via {
+        bereq.append_header("via", via);
+    }
+    config.hooks.on_fragment_dispatch(&mut bereq);
+    #[cfg(feature = "object-storage")]
+    if let Some(storage) = &config.object_storage {
+        storage.sign(
+            &mut bereq,
+            header_str(Some(&config.backend_host)).unwrap_or(""),
+        );
+    }
+    (bereq, span_id)
+}
+
+pub(crate) fn check_identity_encoding(resp: &Response) -> Result<(), Error> {
+    match header_str(resp.get_header("content-encoding")).map(str::trim) {
+        None | Some("") | Some("identity") => Ok(()),
+        Some(encoding) => Err(Error::Encoding(encoding.to_string())),
+    }
+}
+
+pub(crate) fn fragment_request(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    method: Method,
+    range: &str,
+) -> (Request, String) {
+    build_origin_request(req, config, trace, method, Some(range))
+}
+
+pub(crate) fn origin_length(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+) -> Result<Option<usize>, Error> {
+    let (bereq, _) = origin_request(req, config, trace, Method::HEAD);
+    let beresp = bereq
+        .send(&config.backend_name)
+        .map_err(|e| Error::send("backend HEAD request send", e))?;
+    if beresp.get_status() != StatusCode::OK {
+        return Ok(None);
+    }
+    Ok(header_str(beresp.get_header("content-length"))
+        .and_then(|length| length.trim().parse().ok())
+        .filter(|&length| length > 0))
+}
+
+pub(crate) fn all_cached(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    mut frag_req_gen: FragReqGen,
+) -> Result<bool, Error> {
+    // HEAD probes let the caches in front of the origin answer without
+    // moving any of the body.
+    loop {
+        let mut pending = Vec::new();
+        while pending.len() < config.max_in_flight() {
+            let permit = match Permit::try_acquire(config) {
+                Some(permit) => permit,
+                None => break,
+            };
+            let range = match frag_req_gen.next() {
+                Some(range) => range,
+                None => break,
+            };
+            let (bereq, _) = fragment_request(req, config, trace, Method::HEAD, &range);
+            let promise = bereq
+                .send_async(&config.backend_name)
+                .map_err(|e| Error::send("cache probe send_async", e))?;
+            pending.push((promise, permit));
+        }
+        // Blocks left unchecked, with the budget spent on other requests,
+        // may not be cached.
+        if pending.is_empty() {
+            return Ok(frag_req_gen.remaining() == 0);
+        }
+        for (promise, _permit) in pending {
+            let beresp = promise
+                .wait()
+                .map_err(|e| Error::send("cache probe wait", e))?;
+            if !beresp.get_status().is_success() || cache_hit(&beresp) == Some(false) {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+pub(crate) enum FragmentSource {
+    Origin(PendingRequest, Permit),
+    Store(Response),
+}
+
+pub(crate) struct PendingFragment {
+    pub(crate) range: String,
+    pub(crate) first: usize,
+    pub(crate) span_id: String,
+    pub(crate) sent: Instant,
+    pub(crate) source: FragmentSource,
+}
+
+pub(crate) struct FragmentFetcher<'a> {
+    pub(crate) req: &'a Request,
+    pub(crate) config: &'a Config,
+    pub(crate) trace: &'a TraceContext,
+    pub(crate) frag_req_gen: FragReqGen,
+    pub(crate) queue: VecDeque<PendingFragment>,
+    pub(crate) revalidate: bool,
+    pub(crate) store: Option<FragmentStore>,
+    pub(crate) content_digest: Option<String>,
+}
+
+impl<'a> FragmentFetcher<'a> {
+    pub(crate) fn new(
+        req: &'a Request,
+        config: &'a Config,
+        trace: &'a TraceContext,
+        frag_req_gen: FragReqGen,
+    ) -> Self {
+        FragmentFetcher {
+            req,
+            config,
+            trace,
+            frag_req_gen,
+            queue: VecDeque::new(),
+            revalidate: false,
+            store: None,
+            content_digest: None,
+        }
+    }
+
+    /// Fetches and stores blocks by the object's content digest, so objects
+    /// published under several URLs share them.
+    pub(crate) fn share_by_digest(&mut self, digest: Option<&str>) {
+        if let Some(digest) = digest {
+            self.store = self.store.take().map(|store| store.by_digest(digest));
+            self.content_digest = Some(String::from(digest));
+        }
+    }
+
+    pub(crate) fn dispatch(&mut self) -> Result<(), Error> {
+        while self.queue.len() < self.config.parallelism {
+            let first = self.frag_req_gen.position;
+            if let Some(range) = self.frag_req_gen.next() {
+                let stored = match &self.store {
+                    Some(store) if !self.revalidate => store.lookup(first),
+                    _ => None,
+                };
+                if let Some(resp) = stored {
+                    self.queue.push_back(PendingFragment {
+                        range,
+                        first,
+                        span_id: String::new(),
+                        sent: Instant::now(),
+                        source: FragmentSource::Store(resp),
+                    });
+                    continue;
+                }
+                // With nothing queued the fragment goes out regardless, or the
+                // stream would run dry under a low limit.
+                let permit = match Permit::try_acquire(self.config) {
+                    Some(permit) => permit,
+                    None if self.queue.is_empty() => Permit::force(self.config),
+                    None => {
+                        self.frag_req_gen.position = first;
+                        break;
+                    }
+                };
+                let (mut bereq, span_id) =
+                    fragment_request(self.req, self.config, self.trace, Method::GET, &range);
+                // Caches between us and the origin may hold a stale copy.
+                if self.revalidate {
+                    bereq.set_header("cache-control", "no-cache");
+                }
+                if let Some(digest) = &self.content_digest {
+                    bereq.set_header(CONTENT_DIGEST_HEADER, digest);
+                }
+                let promise = bereq
+                    .send_async(&self.config.backend_name)
+                    .map_err(|e| Error::send("backend request send_async", e))?;
+                self.queue.push_back(PendingFragment {
+                    range,
+                    first,
+                    span_id,
+                    sent: Instant::now(),
+                    source: FragmentSource::Origin(promise, permit),
+                });
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn next_response(&mut self) -> Result<Option<(Response, FetchInfo)>, Error> {
+        if let Some(pending) = self.queue.pop_front() {
+            let PendingFragment {
+                range,
+                first,
+                span_id,
+                sent,
+                source,
+            } = pending;
+            let beresp = match source {
+                // The fragment being streamed is out of the count, so the
+                // queue can be refilled right away.
+                FragmentSource::Origin(promise, _permit) => {
+                    let beresp = promise.wait().map_err(|e| {
+                        Error::send(format!("backend request wait for {}", range), e)
+                    })?;
+                    let beresp = self.with_faults(beresp);
+                    match &self.store {
+                        Some(store) => store.save(first, beresp),
+                        None => beresp,
+                    }
+                }
+                FragmentSource::Store(resp) => resp,
+            };
+            let info = FetchInfo {
+                range,
+                span_id,
+                duration: sent.elapsed(),
+            };
+            self.dispatch()?;
+            Ok(Some((beresp, info)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub(crate) fn resume(
+        &mut self,
+        first: usize,
+        last: usize,
+        complete_length: usize,
+    ) -> Result<Fragment, Error> {
+        // The resumed fragment takes the permit every dispatch leaves free.
+        let range = format!("bytes={}-{}", first, last);
+        let (bereq, span_id) =
+            fragment_request(self.req, self.config, self.trace, Method::GET, &range);
+        let sent = Instant::now();
+        let beresp = bereq
+            .send(&self.config.backend_name)
+            .map_err(|e| Error::send("resume backend request send", e))?;
+        let beresp = self.with_faults(beresp);
+        let info = FetchInfo {
+            range,
+            span_id,
+            duration: sent.elapsed(),
+        };
+        let frag = fragment_from_response(beresp, &info, complete_length, self.config)?;
+        if frag.first > first
+            || frag.last < min(last, complete_length - 1)
+            || frag.complete_length != complete_length
+        {
+            return Err(Error::Inconsistency(format!(
+                "resumed fragment content range {}-{}/{} unexpected for request range {}",
+                frag.first, frag.last, frag.complete_length, info.range,
+            )));
+        }
+        Ok(frag.trim(last))
+    }
+
+    /// The stored block holding `position` in place of one the origin
+    /// failed to send, if stored within `window`.
+    pub(crate) fn stale(
+        &self,
+        position: usize,
+        window: Duration,
+        complete_length: usize,
+    ) -> Option<Fragment> {
+        // Queued fragments start where a block of the fetcher's size ends.
+        if self.frag_req_gen.block_size != self.config.block_size {
+            return None;
+        }
+        let beresp = self.store.as_ref()?.stale(position, window)?;
+        let info = FetchInfo {
+            range: format!("bytes={}-", position),
+            span_id: String::new(),
+            duration: Duration::ZERO,
+        };
+        fragment_from_response(beresp, &info, complete_length, self.config).ok()
+    }
+
+    pub(crate) fn refetch(&mut self, position: usize, revalidate: bool) -> Result<(), Error> {
+        let block_size = self.frag_req_gen.block_size;
+        let first = position / block_size * block_size;
+        self.queue.clear();
+        self.frag_req_gen =
+            FragReqGen::new(first, self.frag_req_gen.last, self.config).with_block_size(block_size);
+        self.revalidate |= revalidate;
+        self.dispatch()
+    }
+
+    pub(crate) fn next_position(&self) -> usize {
+        self.queue
+            .front()
+            .map_or(self.frag_req_gen.position, |pending| pending.first)
+    }
+
+    fn with_faults(&self, beresp: Response) -> Response {
+        #[cfg(feature = "chaos")]
+        if self.config.chaos_enabled(self.req) {
+            return crate::chaos::inject(beresp, self.config.chaos_rate);
+        }
+        beresp
+    }
+
+    pub(crate) fn cancel(&mut self) {
+        // Dropping the pending requests closes their handles, so the host
+        // stops reading the responses instead of draining them.
+        self.frag_req_gen.stop();
+        self.queue.clear();
+    }
+}
+
+pub(crate) fn fragment_from_response(
+    beresp: Response,
+    info: &FetchInfo,
+    complete_length: usize,
+    config: &Config,
+) -> Result<Fragment, Error> {
+    with_log(|log| log.current_fragment = Some((info.range.clone(), beresp.get_status())));
+    if beresp.get_status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        let length = ContentRange::unsatisfied_length(&beresp)
+            .map_or_else(|| String::from("unknown"), |length| length.to_string());
+        return Err(Error::ObjectShrank(format!(
+            "{} not satisfiable, complete length now {}",
+            info.range, length
+        )));
+    }
+    if beresp.get_status() != StatusCode::PARTIAL_CONTENT {
+        return Err(Error::OriginStatus(beresp.get_status()));
+    }
+    check_identity_encoding(&beresp)?;
+    let content_range = ContentRange::new(&beresp, Some(complete_length), config)?;
+    with_log(|log| log.fragment(&content_range, &beresp, info));
+    Ok(Fragment::new(beresp, &content_range))
+}
+
+// Fragments are sent to the mock origin, which has to be served as for
+// `tests/mock_origin.rs`.
+#[cfg(all(test, feature = "mock-origin"))]
+mod tests {
+    use super::*;
+    use crate::budget::in_flight;
+    use crate::mock::configure_mock_origin;
+
+    #[test]
+    fn fetches_every_block_with_one_request_in_flight() {
+        let req = Request::get("http://127.0.0.1:7676/obj?size=4194304");
+        let mut config = Config::new(&req);
+        configure_mock_origin(&mut config);
+        config.backend_max_concurrency = 1;
+        let trace = TraceContext::new(&req);
+        let block_size = config.block_size;
+        let frag_req_gen = FragReqGen::new(0, 4 * block_size - 1, &config);
+        let mut fetcher = FragmentFetcher::new(&req, &config, &trace, frag_req_gen);
+        fetcher.dispatch().unwrap();
+        let mut ranges = Vec::new();
+        while let Some((_, info)) = fetcher.next_response().unwrap() {
+            assert!(in_flight(&config) <= 1);
+            ranges.push(info.range);
+        }
+        let expected = (0..4)
+            .map(|block| {
+                let first = block * block_size;
+                format!("bytes={}-{}", first, first + block_size - 1)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, expected);
+        assert_eq!(in_flight(&config), 0);
+    }
+}
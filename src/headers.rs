@@ -0,0 +1,145 @@
+use fastly::http::HeaderValue;
+use fastly::{Request, Response};
+use std::time::Duration;
+
+pub(crate) fn header_str(value: Option<&HeaderValue>) -> Option<&str> {
+    value.and_then(|value| value.to_str().ok())
+}
+
+pub(crate) fn cache_hit(resp: &Response) -> Option<bool> {
+    // The last entry in x-cache was added by the cache closest to us.
+    if let Some(value) =
+        header_str(resp.get_header("x-cache")).and_then(|value| value.rsplit(',').next())
+    {
+        return Some(value.trim().starts_with("HIT"));
+    }
+    header_str(resp.get_header("age"))
+        .and_then(|age| age.trim().parse::<u64>().ok())
+        .map(|age| age > 0)
+}
+
+/// The origin forbids changing the representation on the way to the client.
+pub(crate) fn no_transform(resp: &Response) -> bool {
+    resp.get_header_all("cache-control")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+}
+
+/// The origin marks the object as never changing, as for URLs with a hash
+/// of the content in them.
+pub(crate) fn immutable(resp: &Response) -> bool {
+    resp.get_header_all("cache-control")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("immutable"))
+}
+
+/// How long after a response was received it may still be used when the
+/// origin fails: its freshness lifetime plus its `stale-if-error`.
+pub(crate) fn stale_if_error(resp: &Response) -> Option<Duration> {
+    stale_if_error_window(
+        resp.get_header_all("cache-control")
+            .filter_map(|value| value.to_str().ok()),
+    )
+}
+
+fn stale_if_error_window<'a>(values: impl Iterator<Item = &'a str>) -> Option<Duration> {
+    let (mut max_age, mut s_maxage, mut stale_if_error) = (None, None, None);
+    for directive in values.flat_map(|value| value.split(',')) {
+        let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+        let name = name.trim().to_ascii_lowercase();
+        let seconds = value.trim().trim_matches('"').parse::<u64>().ok();
+        match name.as_str() {
+            "max-age" => max_age = seconds,
+            "s-maxage" => s_maxage = seconds,
+            "stale-if-error" => stale_if_error = seconds,
+            // Stale copies are forbidden whatever else is said.
+            "must-revalidate" | "proxy-revalidate" | "no-store" | "no-cache" => return None,
+            _ => {}
+        }
+    }
+    let lifetime = s_maxage.or(max_age).unwrap_or(0);
+    Some(Duration::from_secs(
+        lifetime.saturating_add(stale_if_error?),
+    ))
+}
+
+pub(crate) const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+pub(crate) fn hop_by_hop_headers<'a>(
+    connection: impl Iterator<Item = &'a HeaderValue>,
+) -> Vec<String> {
+    let mut names = HOP_BY_HOP_HEADERS
+        .iter()
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>();
+    for value in connection.filter_map(|value| value.to_str().ok()) {
+        names.extend(
+            value
+                .split(',')
+                .map(|name| name.trim().to_ascii_lowercase())
+                .filter(|name| !name.is_empty()),
+        );
+    }
+    names
+}
+
+pub(crate) fn strip_request_hop_by_hop(req: &mut Request) {
+    for name in hop_by_hop_headers(req.get_header_all("connection")) {
+        req.remove_header(&name);
+    }
+}
+
+pub(crate) fn strip_response_hop_by_hop(resp: &mut Response) {
+    for name in hop_by_hop_headers(resp.get_header_all("connection")) {
+        resp.remove_header(&name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(values: &[&str]) -> Option<Duration> {
+        stale_if_error_window(values.iter().copied())
+    }
+
+    #[test]
+    fn stale_if_error_extends_the_freshness_lifetime() {
+        assert_eq!(
+            window(&["max-age=60, stale-if-error=3600"]),
+            Some(Duration::from_secs(3660))
+        );
+        assert_eq!(
+            window(&["max-age=60, S-MAXAGE=600", "stale-if-error=\"30\""]),
+            Some(Duration::from_secs(630))
+        );
+        assert_eq!(
+            window(&["stale-if-error=30"]),
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn no_stale_copies_without_stale_if_error() {
+        assert_eq!(window(&[]), None);
+        assert_eq!(window(&["max-age=60"]), None);
+        assert_eq!(window(&["stale-if-error=soon"]), None);
+        assert_eq!(
+            window(&["max-age=60, stale-if-error=30, must-revalidate"]),
+            None
+        );
+        assert_eq!(window(&["no-store", "stale-if-error=30"]), None);
+    }
+}
@@ -0,0 +1,16 @@
+use fastly::{Request, Response};
+
+pub trait Hooks {
+    fn on_discovery(&self, _beresp: &Response) {}
+
+    /// Called for every origin request, with the range already set.
+    fn on_fragment_dispatch(&self, _bereq: &mut Request) {}
+
+    fn on_fragment_complete(&self, _first: usize, _last: usize) {}
+
+    fn on_response_headers(&self, _resp: &mut Response) {}
+}
+
+pub(crate) struct NoHooks;
+
+impl Hooks for NoHooks {}
@@ -0,0 +1,59 @@
+use crate::config::Config;
+use crate::error::SegmentedCacheError as Error;
+use crate::headers::header_str;
+use crate::log::with_log;
+use fastly::Request;
+use sha2::{Digest, Sha256};
+
+/// Names the hex-encoded SHA-256 the response body must have.
+pub(crate) const EXPECTED_DIGEST_HEADER: &str = "x-sc-expected-digest";
+
+/// Hashes the bytes of a whole-object response as they are sent. The last
+/// bytes are held back until the digest is checked, so a client never gets
+/// all of an object that doesn't match.
+pub(crate) struct Verifier {
+    hasher: Sha256,
+    expected: String,
+}
+
+impl Verifier {
+    /// The expected digest of the request, only when
+    /// `Config::trust_expected_digest` says the header can't come from the
+    /// client.
+    pub(crate) fn from_request(req: &Request, config: &Config) -> Result<Option<Self>, Error> {
+        if !config.trust_expected_digest {
+            return Ok(None);
+        }
+        let expected = match header_str(req.get_header(EXPECTED_DIGEST_HEADER)) {
+            Some(expected) => expected.trim().to_ascii_lowercase(),
+            None => return Ok(None),
+        };
+        if expected.len() != 64 || !expected.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(Error::Integrity(format!(
+                "malformed expected digest {}",
+                expected
+            )));
+        }
+        Ok(Some(Verifier {
+            hasher: Sha256::new(),
+            expected,
+        }))
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+    }
+
+    pub(crate) fn verify(self) -> Result<(), Error> {
+        let Verifier { hasher, expected } = self;
+        let actual = format!("{:x}", hasher.finalize());
+        if actual == expected {
+            return Ok(());
+        }
+        with_log(|log| log.integrity_failure(&expected, &actual));
+        Err(Error::Integrity(format!(
+            "sha-256 {} rather than the expected {}",
+            actual, expected
+        )))
+    }
+}
@@ -0,0 +1,217 @@
+/// A parsed JSON value. Numbers are kept as written.
+pub(crate) enum JsonValue {
+    Null,
+    True,
+    False,
+    Number(String),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    pub(crate) fn parse(text: &str) -> Result<Self, &'static str> {
+        let mut parser = JsonParser {
+            bytes: text.as_bytes(),
+            pos: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        if parser.peek().is_some() {
+            return Err("trailing characters after JSON value");
+        }
+        Ok(value)
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+// Deeper nesting than this is refused rather than recursed into.
+const MAX_DEPTH: usize = 32;
+
+struct JsonParser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl JsonParser<'_> {
+    fn peek(&mut self) -> Option<u8> {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.bytes.get(self.pos).copied() {
+            self.pos += 1;
+        }
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn accept(&mut self, byte: u8) -> bool {
+        let found = self.peek() == Some(byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), &'static str> {
+        if self.accept(byte) {
+            Ok(())
+        } else {
+            Err("malformed JSON")
+        }
+    }
+
+    fn value(&mut self) -> Result<JsonValue, &'static str> {
+        match self.peek() {
+            Some(b'"') => self.string().map(JsonValue::String),
+            Some(b'[') => self.nested(|parser| {
+                let mut items = Vec::new();
+                if !parser.accept(b']') {
+                    loop {
+                        items.push(parser.value()?);
+                        if parser.accept(b']') {
+                            break;
+                        }
+                        parser.expect(b',')?;
+                    }
+                }
+                Ok(JsonValue::Array(items))
+            }),
+            Some(b'{') => self.nested(|parser| {
+                let mut members = Vec::new();
+                if !parser.accept(b'}') {
+                    loop {
+                        let name = parser.string()?;
+                        parser.expect(b':')?;
+                        members.push((name, parser.value()?));
+                        if parser.accept(b'}') {
+                            break;
+                        }
+                        parser.expect(b',')?;
+                    }
+                }
+                Ok(JsonValue::Object(members))
+            }),
+            _ => match self.scalar()? {
+                "null" => Ok(JsonValue::Null),
+                "true" => Ok(JsonValue::True),
+                "false" => Ok(JsonValue::False),
+                number if number.parse::<f64>().is_ok() => {
+                    Ok(JsonValue::Number(String::from(number)))
+                }
+                _ => Err("malformed JSON"),
+            },
+        }
+    }
+
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<JsonValue, &'static str>,
+    ) -> Result<JsonValue, &'static str> {
+        if self.depth == MAX_DEPTH {
+            return Err("JSON nested too deeply");
+        }
+        self.depth += 1;
+        self.pos += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn string(&mut self) -> Result<String, &'static str> {
+        self.expect(b'"')?;
+        let mut value = String::new();
+        loop {
+            let byte = *self.bytes.get(self.pos).ok_or("unterminated string")?;
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = *self.bytes.get(self.pos).ok_or("unterminated string")?;
+                    self.pos += 1;
+                    value.push(match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => return Err("invalid escape in string"),
+                    });
+                }
+                _ => {
+                    // Copies the run of unescaped bytes up to here, which
+                    // keeps multi-byte characters whole.
+                    let run_end = self.bytes[self.pos..]
+                        .iter()
+                        .position(|&b| b == b'"' || b == b'\\')
+                        .map_or(self.bytes.len(), |offset| self.pos + offset);
+                    let run = std::str::from_utf8(&self.bytes[self.pos - 1..run_end])
+                        .map_err(|_| "malformed JSON")?;
+                    value.push_str(run);
+                    self.pos = run_end;
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    fn unicode_escape(&mut self) -> Result<char, &'static str> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if self.bytes.get(self.pos..self.pos + 2) != Some(b"\\u") {
+                return Err("invalid escape in string");
+            }
+            self.pos += 2;
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err("invalid escape in string");
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or("invalid escape in string")
+    }
+
+    fn hex4(&mut self) -> Result<u32, &'static str> {
+        let code = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or("invalid escape in string")?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn scalar(&mut self) -> Result<&str, &'static str> {
+        let start = self.pos;
+        while let Some(byte) = self.bytes.get(self.pos) {
+            if !(byte.is_ascii_alphanumeric() || b"+-.".contains(byte)) {
+                break;
+            }
+            self.pos += 1;
+        }
+        match std::str::from_utf8(&self.bytes[start..self.pos]) {
+            Ok(scalar) if !scalar.is_empty() => Ok(scalar),
+            _ => Err("malformed JSON"),
+        }
+    }
+}
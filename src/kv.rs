@@ -0,0 +1,351 @@
+use crate::config::Config;
+use crate::headers::header_str;
+use crate::log::with_log;
+use crate::stream::Validators;
+use fastly::http::StatusCode;
+use fastly::kv_store::{InsertBuilder, KVStore, KVStoreError, LookupResponse};
+use fastly::{Body, Request, Response};
+use sha2::{Digest, Sha256};
+use std::cmp::min;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn object_url(req: &Request, config: &Config) -> String {
+    let url = format!(
+        "{}{}",
+        header_str(Some(&config.backend_host)).unwrap_or(""),
+        req.get_path()
+    );
+    match req.get_query_str() {
+        Some(query) => format!("{}?{}", url, query),
+        None => url,
+    }
+}
+
+/// A key of fixed length, whatever the length of the URL and validator it
+/// is for, since KV Store keys are limited to 1024 bytes.
+fn store_key(kind: &str, parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update(b"\n");
+    }
+    format!("{}#{:x}", kind, hasher.finalize())
+}
+
+/// The surrogate key the caches in front of the origin tag the blocks of
+/// an object with, so they can be purged together. Like the store keys it
+/// has a fixed length, and no spaces, whatever the URL.
+pub(crate) fn surrogate_key(req: &Request, config: &Config) -> String {
+    format!(
+        "sc-{:x}",
+        Sha256::digest(object_url(req, config).as_bytes())
+    )
+}
+
+/// Opens a store, logging why it can't be.
+fn open_store(name: &str) -> Option<KVStore> {
+    match KVStore::open(name) {
+        Ok(Some(store)) => Some(store),
+        Ok(None) => {
+            with_log(|log| log.kv_error("open", name, "no such store"));
+            None
+        }
+        Err(e) => {
+            with_log(|log| log.kv_error("open", name, &e.to_string()));
+            None
+        }
+    }
+}
+
+/// Looks a key up, logging failures other than a miss.
+fn lookup(store: &KVStore, key: &str) -> Option<LookupResponse> {
+    match store.lookup(key) {
+        Ok(found) => Some(found),
+        Err(KVStoreError::ItemNotFound) => None,
+        Err(e) => {
+            with_log(|log| log.kv_error("lookup", key, &e.to_string()));
+            None
+        }
+    }
+}
+
+fn insert(store: &KVStore, key: &str, value: impl Into<Body>) {
+    execute(store.build_insert(), key, value);
+}
+
+fn execute(insert: InsertBuilder, key: &str, value: impl Into<Body>) {
+    if let Err(e) = insert.execute(key, value) {
+        with_log(|log| log.kv_error("insert", key, &e.to_string()));
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// The validator keying stored data for an object. A weak etag doesn't
+/// promise identical bytes.
+fn validator(validators: &Validators) -> Option<&str> {
+    match (&validators.etag, &validators.last_modified) {
+        (Some(etag), _) if !etag.starts_with("W/") => Some(etag.trim_matches('"')),
+        (_, Some(last_modified)) => Some(last_modified.as_str()),
+        _ => None,
+    }
+}
+
+/// The member index of a tar or ZIP archive.
+pub(crate) fn lookup_archive_index(
+    req: &Request,
+    config: &Config,
+    validators: &Validators,
+) -> Option<String> {
+    let store = open_store(config.archive_index_store.as_deref()?)?;
+    let key = store_key(
+        "archive",
+        &[&object_url(req, config), validator(validators)?],
+    );
+    let value = lookup(&store, &key)?.take_body_bytes();
+    String::from_utf8(value).ok()
+}
+
+pub(crate) fn save_archive_index(
+    req: &Request,
+    config: &Config,
+    validators: &Validators,
+    index: &str,
+) {
+    let store = config.archive_index_store.as_deref().and_then(open_store);
+    if let (Some(store), Some(validator)) = (store, validator(validators)) {
+        let key = store_key("archive", &[&object_url(req, config), validator]);
+        insert(&store, &key, index);
+    }
+}
+
+/// The decompressed length of a gzip-compressed object, which only
+/// decompressing all of it tells.
+#[cfg(feature = "decompression")]
+pub(crate) fn lookup_decompressed_length(
+    req: &Request,
+    config: &Config,
+    validators: &Validators,
+) -> Option<usize> {
+    let store = open_store(config.kv_manifest_store.as_deref()?)?;
+    let key = store_key(
+        "gunzip",
+        &[&object_url(req, config), validator(validators)?],
+    );
+    let value = lookup(&store, &key)?.take_body_bytes();
+    String::from_utf8(value).ok()?.parse().ok()
+}
+
+#[cfg(feature = "decompression")]
+pub(crate) fn save_decompressed_length(
+    req: &Request,
+    config: &Config,
+    validators: &Validators,
+    length: usize,
+) {
+    let store = config.kv_manifest_store.as_deref().and_then(open_store);
+    if let (Some(store), Some(validator)) = (store, validator(validators)) {
+        let key = store_key("gunzip", &[&object_url(req, config), validator]);
+        insert(&store, &key, length.to_string());
+    }
+}
+
+/// The manifest listing the parts of a virtual object.
+pub(crate) fn lookup_virtual_object(req: &Request, config: &Config) -> Option<String> {
+    let store = open_store(config.virtual_object_store.as_deref()?)?;
+    let value = lookup(&store, req.get_path())?.take_body_bytes();
+    String::from_utf8(value).ok()
+}
+
+/// The length and validators of an object as of its last discovery.
+pub(crate) struct Manifest {
+    pub(crate) complete_length: usize,
+    pub(crate) validators: Validators,
+}
+
+impl Manifest {
+    pub(crate) fn lookup(req: &Request, config: &Config) -> Option<Self> {
+        let store = open_store(config.kv_manifest_store.as_deref()?)?;
+        let key = store_key("manifest", &[&object_url(req, config)]);
+        let value = lookup(&store, &key)?.take_body_bytes();
+        let value = String::from_utf8(value).ok()?;
+        let mut lines = value.lines();
+        let complete_length = lines.next()?.parse().ok()?;
+        let mut validator = || lines.next().filter(|v| !v.is_empty()).map(String::from);
+        let validators = Validators {
+            etag: validator(),
+            last_modified: validator(),
+            immutable: false,
+        };
+        if validators.etag.is_none() && validators.last_modified.is_none() {
+            return None;
+        }
+        Some(Manifest {
+            complete_length,
+            validators,
+        })
+    }
+
+    pub(crate) fn save(
+        req: &Request,
+        config: &Config,
+        complete_length: usize,
+        validators: &Validators,
+    ) {
+        if validators.etag.is_none() && validators.last_modified.is_none() {
+            return;
+        }
+        if let Some(store) = config.kv_manifest_store.as_deref().and_then(open_store) {
+            let value = format!(
+                "{}\n{}\n{}",
+                complete_length,
+                validators.etag.as_deref().unwrap_or(""),
+                validators.last_modified.as_deref().unwrap_or("")
+            );
+            let key = store_key("manifest", &[&object_url(req, config)]);
+            insert(&store, &key, value);
+        }
+    }
+
+    pub(crate) fn matches(&self, complete_length: usize, validators: &Validators) -> bool {
+        self.complete_length == complete_length && self.validators.matches(validators)
+    }
+}
+
+/// The head blocks of an object kept in a KV Store, keyed by URL, validator
+/// and block so that a new version of the object never matches.
+pub(crate) struct FragmentStore {
+    store: KVStore,
+    key: String,
+    block_size: usize,
+    head_end: usize,
+    complete_length: usize,
+    validators: Validators,
+}
+
+impl FragmentStore {
+    pub(crate) fn open(
+        req: &Request,
+        config: &Config,
+        validators: &Validators,
+        complete_length: usize,
+    ) -> Option<Self> {
+        let name = config.kv_fragment_store.as_deref()?;
+        let validator = validator(validators)?;
+        let store = open_store(name)?;
+        Some(FragmentStore {
+            store,
+            key: store_key(
+                "block",
+                &[
+                    &object_url(req, config),
+                    validator,
+                    &config.block_size.to_string(),
+                ],
+            ),
+            block_size: config.block_size,
+            head_end: config.kv_head_blocks * config.block_size,
+            complete_length,
+            validators: validators.clone(),
+        })
+    }
+
+    /// Keys the blocks by the object's content digest rather than its URL.
+    pub(crate) fn by_digest(mut self, digest: &str) -> Self {
+        self.key = store_key("digest", &[digest, &self.block_size.to_string()]);
+        self
+    }
+
+    fn holds(&self, first: usize) -> bool {
+        first.is_multiple_of(self.block_size) && first < self.head_end
+    }
+
+    fn key(&self, first: usize) -> String {
+        format!("{}#{}", self.key, first / self.block_size)
+    }
+
+    fn last(&self, first: usize) -> usize {
+        min(first + self.block_size, self.complete_length) - 1
+    }
+
+    fn content_range(&self, first: usize) -> String {
+        format!(
+            "bytes {}-{}/{}",
+            first,
+            self.last(first),
+            self.complete_length
+        )
+    }
+
+    /// A stored block as the 206 the origin would have sent.
+    pub(crate) fn lookup(&self, first: usize) -> Option<Response> {
+        if !self.holds(first) {
+            return None;
+        }
+        let found = lookup(&self.store, &self.key(first))?;
+        Some(self.response(first, found, "HIT-KV"))
+    }
+
+    /// The stored block holding `position`, for when the origin fails, if
+    /// it was stored no longer than `window` ago.
+    pub(crate) fn stale(&self, position: usize, window: Duration) -> Option<Response> {
+        let first = position / self.block_size * self.block_size;
+        if !self.holds(first) {
+            return None;
+        }
+        let found = lookup(&self.store, &self.key(first))?;
+        // Blocks stored before their time was recorded are never served stale.
+        let stored = found
+            .metadata()
+            .and_then(|stored| std::str::from_utf8(&stored).ok()?.parse::<u64>().ok())?;
+        if unix_time().saturating_sub(stored) > window.as_secs() {
+            return None;
+        }
+        Some(self.response(first, found, "STALE-KV"))
+    }
+
+    fn response(&self, first: usize, mut found: LookupResponse, x_cache: &str) -> Response {
+        let mut resp = Response::from_status(StatusCode::PARTIAL_CONTENT)
+            .with_header("content-range", self.content_range(first))
+            .with_header("content-length", (self.last(first) - first + 1).to_string())
+            .with_header("x-cache", x_cache)
+            .with_body(found.take_body());
+        if let Some(etag) = &self.validators.etag {
+            resp.set_header("etag", etag);
+        }
+        if let Some(last_modified) = &self.validators.last_modified {
+            resp.set_header("last-modified", last_modified);
+        }
+        resp
+    }
+
+    /// Stores a head block fetched from the origin, which means reading it
+    /// in full before it is streamed on.
+    pub(crate) fn save(&self, first: usize, mut beresp: Response) -> Response {
+        if !self.holds(first)
+            || beresp.get_status() != StatusCode::PARTIAL_CONTENT
+            || header_str(beresp.get_header("content-range")).map(str::trim)
+                != Some(self.content_range(first).as_str())
+            || !Validators::new(&beresp).matches(&self.validators)
+        {
+            return beresp;
+        }
+        let body = beresp.take_body_bytes();
+        if body.len() == self.last(first) - first + 1 {
+            // Writes to a busy key are rate limited; the block is simply
+            // fetched again next time. The time it was stored bounds how
+            // long it may stand in for a failing origin.
+            execute(
+                self.store.build_insert().metadata(&unix_time().to_string()),
+                &self.key(first),
+                &body[..],
+            );
+        }
+        beresp.set_body(body);
+        beresp
+    }
+}
@@ -0,0 +1,712 @@
+mod archive;
+#[cfg(feature = "auth")]
+mod auth;
+mod budget;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod config;
+mod content_range;
+mod coverage;
+#[cfg(feature = "decompression")]
+mod decompress;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod error;
+mod fetch;
+mod headers;
+mod hooks;
+#[cfg(feature = "integrity")]
+mod integrity;
+mod json;
+mod kv;
+mod live;
+mod log;
+#[cfg(feature = "mock-origin")]
+mod mock;
+mod multipart;
+#[cfg(feature = "object-storage")]
+mod object_storage;
+mod plan;
+mod playlist;
+mod precompressed;
+mod prewarm;
+mod purge;
+mod range;
+mod rate_limit;
+mod response;
+#[cfg(feature = "self-test")]
+mod self_test;
+mod stream;
+mod tar;
+mod trace;
+mod virtual_object;
+mod zip;
+
+#[cfg(feature = "auth")]
+pub use crate::auth::{JwtAuth, SignedCookies, UrlSigning};
+pub use crate::config::{
+    Config, ReadaheadProfile, RegionalBackend, SelfTest, SequencePrewarm, TenantProfile,
+};
+#[cfg(feature = "encryption")]
+pub use crate::encryption::Encryption;
+pub use crate::error::{error_response, SegmentedCacheError};
+pub use crate::hooks::Hooks;
+pub use crate::log::log_panic;
+#[cfg(feature = "mock-origin")]
+pub use crate::mock::{configure_mock_origin, serve_mock_origin};
+#[cfg(feature = "object-storage")]
+pub use crate::object_storage::ObjectStorage;
+pub use crate::rate_limit::RateLimit;
+pub use crate::response::{response_headers, set_response_headers};
+
+use crate::archive::{archive_member, member_response};
+use crate::config::strip_query_params;
+use crate::content_range::ContentRange;
+use crate::coverage::{coverage_response, COVERAGE_PATH};
+use crate::error::error_class;
+use crate::fetch::{
+    all_cached, check_identity_encoding, content_digest, fragment_request, origin_length,
+    origin_request, part_block_size, FetchInfo, FragReqGen, FragmentFetcher, CONDITIONAL_HEADERS,
+    CONTENT_DIGEST_HEADER, SURROGATE_CONTROL_HEADER, SURROGATE_KEY_HEADER, TTL_MAX_HEADER,
+    TTL_MIN_HEADER,
+};
+use crate::headers::{cache_hit, strip_request_hop_by_hop, strip_response_hop_by_hop};
+use crate::kv::{lookup_virtual_object, FragmentStore, Manifest};
+use crate::live::{follow_growth, set_live_headers, stream_to_end};
+use crate::log::with_log;
+use crate::multipart::{multipart_ranges, multipart_response};
+use crate::plan::{plan_response, PLAN_PATH};
+use crate::playlist::{is_playlist, playlist_response};
+use crate::precompressed::select_precompressed;
+use crate::prewarm::{
+    background_fill, drain_prefetched, edge_blocks, prefetch_edges, prefetch_next,
+    prewarm_response, PREWARM_PATH,
+};
+use crate::purge::{purge_response, PURGE_PATH};
+use crate::range::{RequestRange, ResolvedRange};
+use crate::response::{
+    empty_object_response, filter_response_headers, hotlink_response, not_cached,
+    preflight_response, range_not_satisfiable, set_compression_hint, set_range_headers,
+};
+#[cfg(feature = "self-test")]
+use crate::self_test::{self_test_response, SELF_TEST_PATH};
+use crate::stream::{stream_fragments, BodyStreamingState, Fragment};
+use crate::trace::{request_id, TraceContext};
+use crate::virtual_object::virtual_response;
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use std::time::Instant;
+
+pub enum Outcome {
+    /// The response was streamed to the client in full.
+    Complete,
+    /// A response for the caller to send, such as a passthrough or a 416.
+    Response(Response),
+    /// The response was streamed to the client but ended early.
+    Aborted(SegmentedCacheError),
+}
+
+/// Errors are only returned while nothing has been sent to the client yet.
+pub fn segmented_fetch(req: Request, config: &mut Config) -> Result<Outcome, SegmentedCacheError> {
+    config.select_for_request(&req);
+    let config = &*config;
+    let mut resp_header_sent = false;
+    let mut resp_headers = Vec::new();
+    let rate_key = config.rate_limit.as_ref().map(|limit| limit.key(&req));
+    let mut result = match serve(req, config, &mut resp_header_sent, &mut resp_headers) {
+        Ok(None) => Ok(Outcome::Complete),
+        Ok(Some(resp)) => Ok(Outcome::Response(resp)),
+        Err(e) if resp_header_sent => Ok(Outcome::Aborted(e)),
+        Err(e) => Err(e),
+    };
+    let (outcome, status, error) = match &result {
+        Ok(Outcome::Complete) => ("complete", None, None),
+        Ok(Outcome::Response(resp)) => ("response", Some(resp.get_status()), None),
+        Ok(Outcome::Aborted(e)) => ("aborted", None, Some(e)),
+        Err(e) => ("error", Some(e.status()), Some(e)),
+    };
+    let request_id = with_log(|log| {
+        let error = error.map(|e| {
+            log.error_class = Some(error_class(e.status()));
+            e.to_string()
+        });
+        if status.is_some() {
+            log.status = status;
+        }
+        log.finish(outcome, error.as_deref());
+        if let (Some(limit), Some(key)) = (&config.rate_limit, &rate_key) {
+            limit.sent(key, log.bytes_sent);
+        }
+        log.request_id.clone()
+    });
+    if let Ok(Outcome::Response(resp)) = &mut result {
+        strip_response_hop_by_hop(resp);
+        resp.set_header("x-request-id", request_id);
+        set_response_headers(resp, &resp_headers);
+        config.hooks.on_response_headers(resp);
+    }
+    result
+}
+
+fn serve(
+    mut req: Request,
+    config: &Config,
+    resp_header_sent: &mut bool,
+    resp_headers: &mut Vec<(String, String)>,
+) -> Result<Option<Response>, SegmentedCacheError> {
+    strip_request_hop_by_hop(&mut req);
+    let request_id = request_id(&req);
+    // Fragment requests are cloned from the client request, so they carry it too.
+    req.set_header("x-request-id", &request_id);
+    let trace = TraceContext::new(&req);
+    with_log(|log| {
+        log.request_id = request_id.clone();
+        log.configure(&req, config, &trace);
+    });
+    let debug = config.debug_enabled(&req);
+    req.remove_header("x-sc-debug");
+    req.remove_header(CONTENT_DIGEST_HEADER);
+    req.remove_header(TTL_MAX_HEADER);
+    req.remove_header(TTL_MIN_HEADER);
+    req.remove_header(SURROGATE_CONTROL_HEADER);
+    req.remove_header(SURROGATE_KEY_HEADER);
+    #[cfg(feature = "integrity")]
+    let mut verifier = crate::integrity::Verifier::from_request(&req, config)?;
+    #[cfg(feature = "integrity")]
+    req.remove_header(crate::integrity::EXPECTED_DIGEST_HEADER);
+    if req.get_path() == PREWARM_PATH && debug {
+        return Ok(prewarm_response(&req, config, &trace));
+    }
+    if req.get_path().starts_with("/__sc/") {
+        return Ok(Some(match req.get_path() {
+            PLAN_PATH if debug => plan_response(&req, config),
+            COVERAGE_PATH if debug => coverage_response(&req, config, &trace),
+            PURGE_PATH if debug => purge_response(&req, config),
+            #[cfg(feature = "self-test")]
+            SELF_TEST_PATH if debug => self_test_response(config),
+            _ => Response::from_status(StatusCode::NOT_FOUND),
+        }));
+    }
+    let mut stripped = vec![
+        config.download_param.as_str(),
+        config.filename_param.as_str(),
+    ];
+    stripped.extend(
+        config
+            .origin_query_params
+            .iter()
+            .map(|(name, _)| name.as_str()),
+    );
+    strip_query_params(&mut req, &stripped);
+    let only_if_cached = config.only_if_cached(&req);
+    if only_if_cached {
+        req.set_header("cache-control", "only-if-cached");
+    }
+    *resp_headers = response_headers(&req, config);
+    if let Some(resp) = preflight_response(&req, config) {
+        return Ok(Some(resp));
+    }
+    if !config.hotlink_allowed(&req) {
+        return Ok(Some(hotlink_response(config)));
+    }
+    if let Some(limit) = &config.rate_limit {
+        if limit.exceeded(&limit.key(&req)) {
+            return Ok(Some(
+                Response::from_status(StatusCode::TOO_MANY_REQUESTS)
+                    .with_header("retry-after", limit.penalty.as_secs().to_string())
+                    .with_body_text_plain("Too many requests\n"),
+            ));
+        }
+    }
+    #[cfg(feature = "auth")]
+    if !crate::auth::authorize(&mut req, config) {
+        return Ok(Some(
+            Response::from_status(StatusCode::FORBIDDEN).with_body_text_plain("Forbidden\n"),
+        ));
+    }
+    // Uploads and API calls under passthrough prefixes go to the origin as is.
+    if !matches!(*req.get_method(), Method::GET | Method::HEAD)
+        && config.passthrough_enabled(req.get_path())
+    {
+        let method = req.get_method().clone();
+        let (mut bereq, _) = origin_request(&req, config, &trace, method);
+        // Preconditions on a write are the client's to make, not a stream's.
+        for name in CONDITIONAL_HEADERS {
+            if let Some(value) = req.get_header(name) {
+                bereq.set_header(name, value.clone());
+            }
+        }
+        if let Some(encoding) = req.get_header("accept-encoding") {
+            bereq.set_header("accept-encoding", encoding.clone());
+        }
+        bereq.set_body(req.take_body());
+        return Ok(Some(bereq.send(&config.backend_name).map_err(|e| {
+            SegmentedCacheError::send("passthrough backend request send", e)
+        })?));
+    }
+    let req_range = RequestRange::new(&req, config).ok().flatten();
+    let header_only = match req.get_method() {
+        &Method::HEAD => true,
+        &Method::GET => false,
+        _ => {
+            return Ok(Some(
+                Response::from_status(StatusCode::METHOD_NOT_ALLOWED)
+                    .with_header("allow", "GET, HEAD")
+                    .with_body_text_plain("Method not allowed\n"),
+            ))
+        }
+    };
+    if req.read_body_chunks(1).next().is_some() {
+        return Ok(Some(
+            Response::from_status(StatusCode::FORBIDDEN)
+                .with_body_text_plain("Request body not allowed\n"),
+        ));
+    }
+
+    if is_playlist(req.get_path()) {
+        return playlist_response(&req, config, &trace).map(Some);
+    }
+    if let Some(member) = archive_member(&req, config) {
+        return member_response(
+            &req,
+            config,
+            &trace,
+            member,
+            header_only,
+            resp_header_sent,
+            resp_headers,
+        );
+    }
+    if let Some(manifest) = lookup_virtual_object(&req, config) {
+        return virtual_response(
+            &req,
+            config,
+            &trace,
+            &manifest,
+            header_only,
+            resp_header_sent,
+            resp_headers,
+        );
+    }
+    #[cfg(feature = "decompression")]
+    if config
+        .gzip_prefixes
+        .iter()
+        .any(|prefix| req.get_path().starts_with(prefix.as_str()))
+    {
+        return crate::decompress::decompressed_response(
+            &req,
+            config,
+            &trace,
+            header_only,
+            resp_header_sent,
+            resp_headers,
+        );
+    }
+    if let Some(parts) = multipart_ranges(&req, config) {
+        return multipart_response(&req, config, &trace, parts, resp_header_sent, resp_headers);
+    }
+
+    // A precompressed sibling is cached and served like any other object.
+    let precompressed = select_precompressed(&mut req, config, &trace);
+
+    // Without caching and parallelism, splitting into blocks only multiplies
+    // origin requests, so fetch the remainder of the range in one go.
+    let single = config.parallelism == 1 && !config.caching_enabled(req.get_path());
+
+    // HEAD is answered from an origin HEAD when it reports the length, so
+    // no block of data has to be served just to be thrown away.
+    if header_only {
+        if let Some(mut resp) = head_response(&req, config, &trace, &req_range)? {
+            if let Some(precompressed) = &precompressed {
+                precompressed.set_headers(&mut resp, config);
+            }
+            return Ok(Some(resp));
+        }
+    }
+
+    // With the length and validators from an earlier discovery, the blocks
+    // after the first are requested along with it rather than after it.
+    let manifest = Manifest::lookup(&req, config);
+    let mut prefetch = manifest
+        .as_ref()
+        .filter(|_| !single && !header_only && !only_if_cached)
+        .and_then(|manifest| {
+            let range = ResolvedRange::new(&req_range, manifest.complete_length)?;
+            let first = range.first / config.block_size * config.block_size;
+            let frag_req_gen = FragReqGen::new(first, range.last, config);
+            if frag_req_gen.remaining() > config.max_fragments
+                || config
+                    .max_object_size
+                    .is_some_and(|max| manifest.complete_length > max)
+            {
+                return None;
+            }
+            let mut fetcher = FragmentFetcher::new(&req, config, &trace, frag_req_gen);
+            fetcher.store =
+                FragmentStore::open(&req, config, &manifest.validators, manifest.complete_length);
+            Some(fetcher)
+        });
+    if let Some(fetcher) = &mut prefetch {
+        fetcher.dispatch()?;
+    }
+
+    #[cfg(feature = "encryption")]
+    let mut decryptor = None;
+    // The origin doesn't know the complete length, and neither does a HEAD.
+    let mut unknown_length = false;
+    let (mut resp, frag1, complete_length, digest) = {
+        let first = req_range.as_ref().map(RequestRange::get_first).unwrap_or(0);
+        let (first, last) = if single {
+            (first, req_range.as_ref().and_then(RequestRange::get_last))
+        } else {
+            let first = first / config.block_size * config.block_size;
+            (first, Some(first + config.block_size - 1))
+        };
+        let range = match last {
+            Some(last) => format!("bytes={}-{}", first, last),
+            None => format!("bytes={}-", first),
+        };
+        let prefetched = match &mut prefetch {
+            Some(fetcher) => fetcher.next_response()?,
+            None => None,
+        };
+        let (beresp, info) = match prefetched {
+            Some(prefetched) => prefetched,
+            None => {
+                let (bereq, span_id) = fragment_request(&req, config, &trace, Method::GET, &range);
+                let sent = Instant::now();
+                let beresp = bereq
+                    .send(&config.backend_name)
+                    .map_err(|e| SegmentedCacheError::send("first backend request send", e))?;
+                let info = FetchInfo {
+                    range,
+                    span_id,
+                    duration: sent.elapsed(),
+                };
+                (beresp, info)
+            }
+        };
+        config.hooks.on_discovery(&beresp);
+        // An origin that ignores the range and sends the whole object is
+        // sliced locally, so a range-requesting client still gets its 206.
+        // Ciphertext is never passed through, so an encrypted one is sliced
+        // even without a client range.
+        let full_body =
+            beresp.get_status() == StatusCode::OK && (req_range.is_some() || config.encrypted());
+        let content_range = match beresp.get_status() {
+            // Origins that don't know the complete length may still report it
+            // on HEAD; otherwise the object is streamed until the origin has
+            // no more, taking the bytes the first block holds as the length
+            // so far.
+            StatusCode::PARTIAL_CONTENT if ContentRange::has_unknown_length(&beresp) => {
+                match origin_length(&req, config, &trace)? {
+                    Some(length) => ContentRange::new(&beresp, Some(length), config)
+                        .map_err(|e| e.context("first backend response"))?,
+                    None if config.encrypted() => {
+                        return Err(SegmentedCacheError::Decryption("complete length unknown"))
+                    }
+                    None => {
+                        let mut content_range =
+                            ContentRange::new(&beresp, Some(usize::MAX), config)
+                                .map_err(|e| e.context("first backend response"))?;
+                        content_range.complete_length = content_range.last + 1;
+                        // A block cut short ends the object.
+                        unknown_length = last.is_none_or(|last| content_range.last >= last);
+                        content_range
+                    }
+                }
+            }
+            StatusCode::PARTIAL_CONTENT => ContentRange::new(&beresp, None, config)
+                .map_err(|e| e.context("first backend response"))?,
+            StatusCode::RANGE_NOT_SATISFIABLE if ContentRange::is_empty_object(&beresp) => {
+                return Ok(Some(empty_object_response(
+                    &beresp,
+                    req_range.is_some(),
+                    config,
+                )))
+            }
+            StatusCode::OK if full_body && ContentRange::is_empty_object(&beresp) => {
+                return Ok(Some(empty_object_response(&beresp, true, config)))
+            }
+            StatusCode::OK
+                if full_body
+                    && !config.encrypted()
+                    && check_identity_encoding(&beresp).is_err() =>
+            {
+                return Ok(Some(beresp))
+            }
+            StatusCode::OK if full_body => match ContentRange::from_full_body(&beresp) {
+                Some(content_range) => content_range,
+                None if config.encrypted() => {
+                    return Err(SegmentedCacheError::Decryption("complete length unknown"))
+                }
+                None => return Ok(Some(beresp)),
+            },
+            _ => return Ok(Some(beresp)),
+        };
+        with_log(|log| log.discovery(&content_range, &beresp, &info));
+        if only_if_cached && cache_hit(&beresp) == Some(false) {
+            return Ok(Some(not_cached()));
+        }
+        check_identity_encoding(&beresp).map_err(|e| e.context("first backend response"))?;
+        if !full_body && content_range.first > first {
+            return Err(SegmentedCacheError::Inconsistency(format!(
+                "fragment content range {}-{} unexpected for request range {}",
+                content_range.first, content_range.last, info.range,
+            ))
+            .context("first backend response"));
+        }
+        #[cfg(feature = "encryption")]
+        if let (Some(encryption), false) = (&config.encryption, header_only) {
+            decryptor = Some(
+                encryption
+                    .decryptor(&req, config, &trace, &beresp)
+                    .map_err(|e| e.context("first backend response"))?,
+            );
+        }
+        let digest = content_digest(config, &beresp);
+        let mut resp = beresp.clone_without_body();
+        filter_response_headers(&mut resp, config);
+        let frag1 = Fragment::new(beresp, &content_range);
+        let frag1 = match last {
+            Some(last) if !full_body => frag1.trim(last),
+            _ => frag1,
+        };
+        let complete_length = content_range.complete_length;
+        if !unknown_length
+            && !manifest
+                .as_ref()
+                .is_some_and(|manifest| manifest.matches(complete_length, &frag1.validators))
+        {
+            Manifest::save(&req, config, complete_length, &frag1.validators);
+        }
+        (resp, frag1, complete_length, digest)
+    };
+
+    let range = if let Some(range) = ResolvedRange::new(&req_range, complete_length) {
+        range
+    } else {
+        return Ok(Some(range_not_satisfiable(complete_length)));
+    };
+    set_range_headers(&mut resp, &req_range, &range, complete_length, config);
+    let live = !header_only
+        && req_range.as_ref().is_none_or(|r| r.get_last().is_none())
+        && config.live_enabled(req.get_path());
+    if live {
+        set_live_headers(&mut resp, &range);
+    }
+    // A closed range is claimed in full, though the object may end first.
+    let client_last = req_range.as_ref().and_then(RequestRange::get_last);
+    if unknown_length {
+        set_live_headers(
+            &mut resp,
+            &ResolvedRange {
+                first: range.first,
+                last: client_last.unwrap_or(range.last),
+            },
+        );
+    }
+    if let Some(precompressed) = &precompressed {
+        precompressed.set_headers(&mut resp, config);
+    }
+    if req_range.is_none() {
+        set_compression_hint(&req, &mut resp, config);
+    }
+
+    // The first block is requested before the ETag is seen, so only the
+    // blocks after it line up with the origin's upload parts.
+    let block_size = part_block_size(config, frag1.validators.etag.as_deref(), complete_length);
+    let frag_req_gen =
+        FragReqGen::new(frag1.last + 1, range.last, config).with_block_size(block_size);
+    if only_if_cached
+        && !all_cached(
+            &req,
+            config,
+            &trace,
+            FragReqGen::new(frag1.last + 1, range.last, config).with_block_size(block_size),
+        )?
+    {
+        return Ok(Some(not_cached()));
+    }
+    if config
+        .max_object_size
+        .is_some_and(|max| complete_length > max)
+        || 1 + frag_req_gen.remaining() > config.max_fragments
+    {
+        return Ok(Some(
+            Response::from_status(StatusCode::PAYLOAD_TOO_LARGE)
+                .with_body_text_plain("Object too large to segment\n"),
+        ));
+    }
+    if debug {
+        resp.set_header(
+            "x-sc-debug-range",
+            format!("{}-{}", range.first, range.last),
+        );
+        resp.set_header("x-sc-debug-block-size", block_size.to_string());
+        resp.set_header("x-sc-debug-parallelism", config.parallelism.to_string());
+        resp.set_header(
+            "x-sc-debug-fragments",
+            (1 + frag_req_gen.remaining()).to_string(),
+        );
+        resp.set_header("x-sc-debug-backend", &config.backend_name);
+        resp.set_header(
+            "x-sc-debug-cache-hits",
+            with_log(|log| format!("{}/{}", log.cache_hits, log.cache_lookups)),
+        );
+    }
+
+    resp.set_header("server-timing", with_log(|log| log.server_timing()));
+    resp.set_header("x-request-id", &request_id);
+    if !header_only {
+        resp.set_header("trailer", "server-timing, x-sc-status, x-sc-bytes-sent");
+    }
+    set_response_headers(&mut resp, resp_headers);
+    config.hooks.on_response_headers(&mut resp);
+    with_log(|log| log.status = Some(resp.get_status()));
+    let edges = edge_blocks(&req, &resp, config, &range);
+    let resp_body = resp.stream_to_client();
+    *resp_header_sent = true;
+    if header_only {
+        return resp_body
+            .finish()
+            .map(|()| None)
+            .map_err(|_| SegmentedCacheError::ClientGone);
+    }
+
+    // Get the next fragments in flight before discarding the leading bytes
+    // of the first one, and keep the queue full while writing to the client.
+    let mut fetcher = match prefetch {
+        Some(fetcher)
+            if fetcher.next_position() == frag1.last + 1
+                && block_size == config.block_size
+                && manifest.as_ref().is_some_and(|manifest| {
+                    manifest.matches(complete_length, &frag1.validators)
+                }) =>
+        {
+            fetcher
+        }
+        _ => {
+            let mut fetcher = FragmentFetcher::new(&req, config, &trace, frag_req_gen);
+            // Blocks in the KV Store have the configured size.
+            if block_size == config.block_size {
+                fetcher.store =
+                    FragmentStore::open(&req, config, &frag1.validators, complete_length);
+            }
+            fetcher.share_by_digest(digest.as_deref());
+            fetcher
+        }
+    };
+    fetcher.dispatch()?;
+    let prefetched = prefetch_edges(
+        &req,
+        config,
+        &trace,
+        &range,
+        complete_length,
+        edges,
+        digest.as_deref(),
+    );
+
+    let validators = frag1.validators.clone();
+    let mut state = BodyStreamingState::new(&range, resp_body, config);
+    #[cfg(feature = "encryption")]
+    {
+        state.decryptor = decryptor;
+    }
+    // A digest covers the whole object, not a range of it.
+    #[cfg(feature = "integrity")]
+    if range.first == 0 && range.last + 1 == complete_length && !live && !unknown_length {
+        state.verifier = verifier.take();
+    }
+    let result = stream_fragments(&mut state, &mut fetcher, frag1, complete_length);
+    fetcher.cancel();
+    let mut result = result.and(state.flush());
+    if live && result.is_ok() {
+        result = follow_growth(&mut state, &req, config, &trace);
+    } else if unknown_length && result.is_ok() {
+        result = stream_to_end(&mut state, &req, config, &trace, client_last);
+    }
+    let server_timing = with_log(|log| {
+        log.bytes_sent = state.bytes_sent();
+        log.discarded_bytes = state.discarded;
+        if let Err(e) = &result {
+            log.truncated(state.position, range.last, e);
+        }
+        log.server_timing()
+    });
+    let bytes_sent = state.bytes_sent();
+    let resp_body = &mut state.resp_body;
+    resp_body.append_trailer("server-timing", server_timing);
+    resp_body.append_trailer(
+        "x-sc-status",
+        if result.is_ok() {
+            "complete"
+        } else {
+            "aborted"
+        },
+    );
+    resp_body.append_trailer("x-sc-bytes-sent", bytes_sent.to_string());
+    result?;
+
+    // A body dropped unfinished is aborted, trailers and all.
+    state
+        .resp_body
+        .finish()
+        .map_err(|_| SegmentedCacheError::ClientGone)?;
+
+    // The client has the whole response before any block it didn't ask for
+    // is read.
+    drain_prefetched(prefetched);
+    // A growing object was followed to its current end already.
+    if config.background_fill_blocks > 0 && !live && !unknown_length {
+        background_fill(
+            &req,
+            config,
+            &trace,
+            range.last + 1,
+            complete_length,
+            &validators,
+            digest.as_deref(),
+        );
+    }
+    drain_prefetched(prefetch_next(&req, config, &trace));
+
+    Ok(None)
+}
+
+fn head_response(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    req_range: &Option<RequestRange>,
+) -> Result<Option<Response>, SegmentedCacheError> {
+    let (bereq, _) = origin_request(req, config, trace, Method::HEAD);
+    let mut beresp = bereq
+        .send(&config.backend_name)
+        .map_err(|e| SegmentedCacheError::send("backend HEAD request send", e))?;
+    match beresp.get_status() {
+        StatusCode::OK => (),
+        StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => return Ok(None),
+        _ => return Ok(Some(beresp)),
+    }
+    if ContentRange::is_empty_object(&beresp) {
+        return Ok(Some(empty_object_response(
+            &beresp,
+            req_range.is_some(),
+            config,
+        )));
+    }
+    let complete_length = match ContentRange::from_full_body(&beresp) {
+        Some(content_range) => content_range.complete_length,
+        None => return Ok(None),
+    };
+    let range = match ResolvedRange::new(req_range, complete_length) {
+        Some(range) => range,
+        None => return Ok(Some(range_not_satisfiable(complete_length))),
+    };
+    filter_response_headers(&mut beresp, config);
+    set_range_headers(&mut beresp, req_range, &range, complete_length, config);
+    Ok(Some(beresp))
+}
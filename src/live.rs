@@ -0,0 +1,113 @@
+use crate::config::Config;
+use crate::content_range::ContentRange;
+use crate::error::SegmentedCacheError as Error;
+use crate::fetch::fragment_request;
+use crate::range::ResolvedRange;
+use crate::stream::{BodyStreamingState, Fragment};
+use crate::trace::TraceContext;
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use std::time::Instant;
+
+/// Headers for a growing object's response, whose end isn't known yet: no
+/// `Content-Length`, and `*` as the complete length.
+pub(crate) fn set_live_headers(resp: &mut Response, range: &ResolvedRange) {
+    resp.remove_header("content-length");
+    if resp.get_header("content-range").is_some() {
+        resp.set_header(
+            "content-range",
+            format!("bytes {}-{}/*", range.first, range.last),
+        );
+    }
+}
+
+/// Streams what the origin appends to the object after the bytes sent so
+/// far, polling every `Config::live_poll_interval` while it has nothing
+/// new. Stops once `Config::live_budget` is spent or `Config::live_max_bytes`
+/// are sent, or at the first origin error, which ends the response like the
+/// end of the object would.
+pub(crate) fn follow_growth(
+    state: &mut BodyStreamingState,
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+) -> Result<(), Error> {
+    let deadline = Instant::now() + config.live_budget;
+    let limit = state.first.saturating_add(config.live_max_bytes);
+    read_on(state, req, config, trace, limit, Some(deadline))
+}
+
+/// Streams the rest of an object whose origin doesn't know its length, a
+/// block at a time, until the origin has nothing after the bytes sent or
+/// `last` is reached. Origin errors fail the response.
+pub(crate) fn stream_to_end(
+    state: &mut BodyStreamingState,
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    last: Option<usize>,
+) -> Result<(), Error> {
+    let limit = last.map_or(usize::MAX, |last| last.saturating_add(1));
+    let limit = config.max_object_size.map_or(limit, |max| limit.min(max));
+    read_on(state, req, config, trace, limit, None)
+}
+
+/// Reads on past the bytes sent, up to `limit`. Without a deadline the
+/// object isn't growing, so a `416` is its end and anything else unexpected
+/// is an error.
+fn read_on(
+    state: &mut BodyStreamingState,
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    limit: usize,
+    deadline: Option<Instant>,
+) -> Result<(), Error> {
+    while state.position < limit && deadline.is_none_or(|deadline| Instant::now() < deadline) {
+        let first = state.position;
+        let last = (first / config.block_size + 1) * config.block_size - 1;
+        let range = format!("bytes={}-{}", first, last);
+        let (mut bereq, _) = fragment_request(req, config, trace, Method::GET, &range);
+        // A cached 416 or short block would hide the new bytes.
+        if deadline.is_some() {
+            bereq.set_header("cache-control", "no-cache");
+        }
+        let beresp = match bereq.send(&config.backend_name) {
+            Ok(beresp) => beresp,
+            Err(e) if deadline.is_none() => return Err(Error::send("backend request send", e)),
+            Err(_) => return Ok(()),
+        };
+        match beresp.get_status() {
+            StatusCode::PARTIAL_CONTENT => {
+                // The origin may not know the length of a growing object.
+                let content_range = match ContentRange::new(&beresp, Some(usize::MAX), config) {
+                    Ok(content_range) if content_range.first == first => content_range,
+                    Ok(_) if deadline.is_none() => {
+                        return Err(Error::Inconsistency(format!(
+                            "fragment content range unexpected for request range {}",
+                            range
+                        )))
+                    }
+                    Err(e) if deadline.is_none() => return Err(e),
+                    _ => return Ok(()),
+                };
+                state.last = content_range.last.min(limit - 1);
+                let frag = Fragment::new(beresp, &content_range).trim(state.last);
+                state.send_fragment(frag)?;
+                state.flush()?;
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE => match deadline {
+                Some(deadline) => {
+                    let wait = config
+                        .live_poll_interval
+                        .min(deadline.saturating_duration_since(Instant::now()));
+                    std::thread::sleep(wait);
+                }
+                None => return Ok(()),
+            },
+            status if deadline.is_none() => return Err(Error::OriginStatus(status)),
+            _ => return Ok(()),
+        }
+    }
+    Ok(())
+}
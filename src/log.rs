@@ -0,0 +1,462 @@
+use crate::config::Config;
+use crate::content_range::ContentRange;
+use crate::error::SegmentedCacheError;
+use crate::fetch::FetchInfo;
+use crate::headers::{cache_hit, header_str};
+use crate::trace::TraceContext;
+use fastly::http::StatusCode;
+use fastly::log::Endpoint;
+use fastly::{Request, Response};
+use std::cell::RefCell;
+use std::cmp::min;
+use std::collections::hash_map::RandomState;
+use std::fmt::{Display, Write as _};
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::panic::PanicHookInfo;
+use std::time::{Duration, Instant};
+
+pub(crate) struct JsonRecord {
+    pub(crate) buf: String,
+}
+
+impl JsonRecord {
+    pub(crate) fn new() -> Self {
+        JsonRecord {
+            buf: String::from("{"),
+        }
+    }
+
+    pub(crate) fn key(&mut self, key: &str) -> &mut String {
+        if self.buf.len() > 1 {
+            self.buf.push(',');
+        }
+        push_json_str(&mut self.buf, key);
+        self.buf.push(':');
+        &mut self.buf
+    }
+
+    pub(crate) fn str(&mut self, key: &str, value: &str) -> &mut Self {
+        push_json_str(self.key(key), value);
+        self
+    }
+
+    pub(crate) fn opt_str(&mut self, key: &str, value: Option<&str>) -> &mut Self {
+        match value {
+            Some(value) => self.str(key, value),
+            None => self.raw(key, "null"),
+        }
+    }
+
+    pub(crate) fn num(&mut self, key: &str, value: impl Display) -> &mut Self {
+        let _ = write!(self.key(key), "{}", value);
+        self
+    }
+
+    pub(crate) fn raw(&mut self, key: &str, value: &str) -> &mut Self {
+        self.key(key).push_str(value);
+        self
+    }
+
+    pub(crate) fn finish(&mut self) -> String {
+        self.buf.push('}');
+        std::mem::take(&mut self.buf)
+    }
+}
+
+pub(crate) fn push_json_str(buf: &mut String, value: &str) {
+    buf.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(buf, "\\u{:04x}", c as u32);
+            }
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
+
+thread_local! {
+    static REQUEST_LOG: RefCell<RequestLog> = RefCell::new(RequestLog::new());
+}
+
+pub(crate) fn with_log<T>(f: impl FnOnce(&mut RequestLog) -> T) -> T {
+    REQUEST_LOG.with(|log| f(&mut log.borrow_mut()))
+}
+
+pub fn log_panic(info: &PanicHookInfo) {
+    eprintln!("PANIC: {}", info);
+    // Compute@Edge aborts on panic, so this hook is the last chance to
+    // emit the summary record.
+    let _ = REQUEST_LOG.try_with(|log| {
+        if let Ok(mut log) = log.try_borrow_mut() {
+            log.finish("panic", Some(&info.to_string()));
+        }
+    });
+}
+
+pub(crate) const LATENCY_BUCKETS_MS: [u64; 8] = [10, 25, 50, 100, 250, 500, 1000, 2500];
+
+pub(crate) struct RequestLog {
+    pub(crate) start: Instant,
+    pub(crate) endpoint: Option<Endpoint>,
+    pub(crate) metrics_endpoint: Option<Endpoint>,
+    pub(crate) log_fragments: bool,
+    pub(crate) log_error_sample_rate: f64,
+    pub(crate) log_success_sample_rate: f64,
+    pub(crate) log_always: bool,
+    pub(crate) records: Vec<String>,
+    pub(crate) pop: Option<String>,
+    pub(crate) service_version: Option<String>,
+    pub(crate) fastly_trace_id: Option<String>,
+    pub(crate) tags: Vec<(String, String)>,
+    pub(crate) request_id: String,
+    pub(crate) object: String,
+    pub(crate) trace_id: String,
+    pub(crate) parent_id: Option<String>,
+    pub(crate) range: Option<String>,
+    pub(crate) block_size: usize,
+    pub(crate) fragments: usize,
+    pub(crate) background_fragments: usize,
+    pub(crate) retries: usize,
+    pub(crate) degraded: bool,
+    pub(crate) origin_bytes: usize,
+    pub(crate) discarded_bytes: usize,
+    pub(crate) cache_lookups: usize,
+    pub(crate) cache_hits: usize,
+    pub(crate) bytes_sent: usize,
+    pub(crate) status: Option<StatusCode>,
+    pub(crate) error_class: Option<&'static str>,
+    pub(crate) finished: bool,
+    pub(crate) current_fragment: Option<(String, StatusCode)>,
+    pub(crate) discovery: Option<Duration>,
+    pub(crate) fetch_min: Duration,
+    pub(crate) fetch_max: Duration,
+    pub(crate) fetch_total: Duration,
+    pub(crate) fetch_histogram: [usize; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+impl RequestLog {
+    pub(crate) fn new() -> Self {
+        RequestLog {
+            start: Instant::now(),
+            endpoint: None,
+            metrics_endpoint: None,
+            log_fragments: false,
+            log_error_sample_rate: 1.0,
+            log_success_sample_rate: 1.0,
+            log_always: false,
+            records: Vec::new(),
+            pop: std::env::var("FASTLY_POP").ok(),
+            service_version: std::env::var("FASTLY_SERVICE_VERSION").ok(),
+            fastly_trace_id: std::env::var("FASTLY_TRACE_ID").ok(),
+            tags: Vec::new(),
+            request_id: String::new(),
+            object: String::new(),
+            trace_id: String::new(),
+            parent_id: None,
+            range: None,
+            block_size: 0,
+            fragments: 0,
+            background_fragments: 0,
+            retries: 0,
+            degraded: false,
+            origin_bytes: 0,
+            discarded_bytes: 0,
+            cache_lookups: 0,
+            cache_hits: 0,
+            bytes_sent: 0,
+            status: None,
+            error_class: None,
+            finished: false,
+            current_fragment: None,
+            discovery: None,
+            fetch_min: Duration::MAX,
+            fetch_max: Duration::ZERO,
+            fetch_total: Duration::ZERO,
+            fetch_histogram: [0; LATENCY_BUCKETS_MS.len() + 1],
+        }
+    }
+
+    pub(crate) fn configure(&mut self, req: &Request, config: &Config, trace: &TraceContext) {
+        // Without structured logging no endpoint is opened, so no record is
+        // ever built.
+        #[cfg(feature = "structured-logging")]
+        {
+            self.endpoint = config
+                .log_endpoint
+                .as_deref()
+                .and_then(|name| Endpoint::try_from_name(name).ok());
+            self.metrics_endpoint = config
+                .metrics_endpoint
+                .as_deref()
+                .and_then(|name| Endpoint::try_from_name(name).ok());
+            self.log_fragments = config.log_fragments;
+        }
+        self.log_error_sample_rate = config.log_error_sample_rate;
+        self.log_success_sample_rate = config.log_success_sample_rate;
+        self.log_always = config.debug_enabled(req);
+        self.tags = config.log_tags.clone();
+        self.object = req.get_path().to_string();
+        self.trace_id = trace.trace_id.clone();
+        self.parent_id = trace.parent_id.clone();
+        self.range = header_str(req.get_header("range")).map(String::from);
+        self.block_size = config.block_size;
+    }
+
+    pub(crate) fn discovery(
+        &mut self,
+        content_range: &ContentRange,
+        beresp: &Response,
+        info: &FetchInfo,
+    ) {
+        self.discovery = Some(info.duration);
+        self.current_fragment = Some((info.range.clone(), beresp.get_status()));
+        self.fragment(content_range, beresp, info);
+    }
+
+    pub(crate) fn fragment(
+        &mut self,
+        content_range: &ContentRange,
+        beresp: &Response,
+        info: &FetchInfo,
+    ) {
+        let duration = info.duration;
+        let hit = cache_hit(beresp);
+        if let Some(hit) = hit {
+            self.cache_lookups += 1;
+            self.cache_hits += hit as usize;
+        }
+        self.fragments += 1;
+        self.origin_bytes += content_range.last - content_range.first + 1;
+        self.fetch_min = min(self.fetch_min, duration);
+        self.fetch_max = self.fetch_max.max(duration);
+        self.fetch_total += duration;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| duration < Duration::from_millis(bound))
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.fetch_histogram[bucket] += 1;
+        if self.log_fragments {
+            let record = self
+                .record("fragment")
+                .str("object", &self.object)
+                .str("trace_id", &self.trace_id)
+                .str("span_id", &info.span_id)
+                .num("first", content_range.first)
+                .num("last", content_range.last)
+                .num("status", beresp.get_status().as_u16())
+                .opt_str("cache", hit.map(|hit| if hit { "hit" } else { "miss" }))
+                .opt_str("age", header_str(beresp.get_header("age")))
+                .num("duration_us", duration.as_micros())
+                .finish();
+            self.emit(&record);
+        }
+    }
+
+    pub(crate) fn finish(&mut self, outcome: &str, error: Option<&str>) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        if self.endpoint.is_none() && self.metrics_endpoint.is_none() {
+            return;
+        }
+        let record = self
+            .record("request")
+            .str("object", &self.object)
+            .str("trace_id", &self.trace_id)
+            .opt_str("parent_id", self.parent_id.as_deref())
+            .opt_str("range", self.range.as_deref())
+            .num("block_size", self.block_size)
+            .num("fragments", self.fragments)
+            .num("background_fragments", self.background_fragments)
+            .raw("fetch_latency_ms", &self.latency_histogram())
+            .num("cache_lookups", self.cache_lookups)
+            .num("cache_hits", self.cache_hits)
+            .raw("cache_hit_ratio", &self.cache_hit_ratio())
+            .num("bytes_sent", self.bytes_sent)
+            .num("discarded_bytes", self.discarded_bytes)
+            .num("degraded", self.degraded)
+            .num("duration_us", self.start.elapsed().as_micros())
+            .raw(
+                "status",
+                &self.status.map_or_else(
+                    || String::from("null"),
+                    |status| status.as_u16().to_string(),
+                ),
+            )
+            .str("outcome", outcome)
+            .opt_str("error", error)
+            .finish();
+        self.emit(&record);
+        let rate = if error.is_some() {
+            self.log_error_sample_rate
+        } else {
+            self.log_success_sample_rate
+        };
+        if self.log_always || rate >= 1.0 || (random_u64() as f64) < rate * (u64::MAX as f64) {
+            if let Some(endpoint) = &mut self.endpoint {
+                for record in &self.records {
+                    let _ = writeln!(endpoint, "{}", record);
+                }
+            }
+        }
+        self.records.clear();
+        self.emit_metrics();
+    }
+
+    pub(crate) fn record(&self, record_type: &str) -> JsonRecord {
+        let mut record = JsonRecord::new();
+        record
+            .str("type", record_type)
+            .str("request_id", &self.request_id)
+            .opt_str("pop", self.pop.as_deref())
+            .opt_str("service_version", self.service_version.as_deref())
+            .opt_str("fastly_trace_id", self.fastly_trace_id.as_deref());
+        for (key, value) in &self.tags {
+            record.str(key, value);
+        }
+        record
+    }
+
+    pub(crate) fn cache_hit_ratio(&self) -> String {
+        if self.cache_lookups > 0 {
+            format!("{:.3}", self.cache_hits as f64 / self.cache_lookups as f64)
+        } else {
+            String::from("null")
+        }
+    }
+
+    pub(crate) fn latency_histogram(&self) -> String {
+        let mut histogram = JsonRecord::new();
+        for (bound, count) in LATENCY_BUCKETS_MS.iter().zip(&self.fetch_histogram) {
+            histogram.num(&format!("lt_{}", bound), count);
+        }
+        histogram.num("inf", self.fetch_histogram[LATENCY_BUCKETS_MS.len()]);
+        histogram.finish()
+    }
+
+    pub(crate) fn truncated(&mut self, offset: usize, last: usize, error: &SegmentedCacheError) {
+        let (fragment, status) = match &self.current_fragment {
+            Some((range, status)) => (Some(range.as_str()), status.as_u16().to_string()),
+            None => (None, String::from("null")),
+        };
+        let record = self
+            .record("truncated")
+            .str("object", &self.object)
+            .num("offset", offset)
+            .num("last", last)
+            .opt_str("fragment", fragment)
+            .raw("origin_status", &status)
+            .str("error", &error.to_string())
+            .finish();
+        self.emit(&record);
+    }
+
+    pub(crate) fn kv_error(&mut self, operation: &str, key: &str, error: &str) {
+        let record = self
+            .record("kv_error")
+            .str("object", &self.object)
+            .str("operation", operation)
+            .str("key", key)
+            .str("error", error)
+            .finish();
+        self.emit(&record);
+    }
+
+    #[cfg(feature = "integrity")]
+    pub(crate) fn integrity_failure(&mut self, expected: &str, actual: &str) {
+        let record = self
+            .record("integrity_failure")
+            .str("object", &self.object)
+            .str("expected_sha256", expected)
+            .str("sha256", actual)
+            .finish();
+        self.emit(&record);
+    }
+
+    pub(crate) fn degraded(&mut self, offset: usize, failures: usize, error: &SegmentedCacheError) {
+        self.degraded = true;
+        let record = self
+            .record("degraded")
+            .str("object", &self.object)
+            .num("offset", offset)
+            .num("failures", failures)
+            .str("error", &error.to_string())
+            .finish();
+        self.emit(&record);
+    }
+
+    pub(crate) fn stale(&mut self, offset: usize, error: &SegmentedCacheError) {
+        let record = self
+            .record("stale")
+            .str("object", &self.object)
+            .num("offset", offset)
+            .str("error", &error.to_string())
+            .finish();
+        self.emit(&record);
+    }
+
+    pub(crate) fn emit_metrics(&mut self) {
+        let mut errors = JsonRecord::new();
+        if let Some(class) = self.error_class {
+            errors.num(class, 1);
+        }
+        let record = self
+            .record("metrics")
+            .num("requests", 1)
+            .num("fragments", self.fragments)
+            .num("retries", self.retries)
+            .num("origin_bytes", self.origin_bytes)
+            .num("client_bytes", self.bytes_sent)
+            .num("discarded_bytes", self.discarded_bytes)
+            .raw("errors", &errors.finish())
+            .finish();
+        if let Some(endpoint) = &mut self.metrics_endpoint {
+            let _ = writeln!(endpoint, "{}", record);
+        }
+    }
+
+    pub(crate) fn server_timing(&self) -> String {
+        let mut value = String::new();
+        if let Some(discovery) = self.discovery {
+            let _ = write!(value, "sc-discovery;dur={}, ", millis(discovery));
+        }
+        if self.fragments > 0 {
+            let _ = write!(
+                value,
+                "sc-fragments;desc={}, sc-fetch-min;dur={}, sc-fetch-avg;dur={}, sc-fetch-max;dur={}, ",
+                self.fragments,
+                millis(self.fetch_min),
+                millis(self.fetch_total / self.fragments as u32),
+                millis(self.fetch_max),
+            );
+        }
+        let _ = write!(value, "sc-total;dur={}", millis(self.start.elapsed()));
+        value
+    }
+
+    pub(crate) fn emit(&mut self, record: &str) {
+        // Records are held until the outcome is known and the request is
+        // sampled in or out.
+        if self.endpoint.is_some() {
+            self.records.push(record.to_string());
+        }
+    }
+}
+
+pub(crate) fn millis(duration: Duration) -> String {
+    format!("{:.1}", duration.as_secs_f64() * 1000.0)
+}
+
+pub(crate) fn random_u64() -> u64 {
+    // RandomState is seeded from the host's random source.
+    RandomState::new().build_hasher().finish()
+}
@@ -0,0 +1,111 @@
+use crate::config::Config;
+use crate::hooks::Hooks;
+use crate::range::{parse_digits, RequestRange, ResolvedRange};
+use fastly::http::{HeaderValue, Method, StatusCode};
+use fastly::{Request, Response};
+use std::io::Write;
+
+const MOCK_HEADER: &str = "x-sc-mock-origin";
+
+/// Points the service at itself, so that origin requests are answered by
+/// `serve_mock_origin` with synthetic content.
+pub fn configure_mock_origin(config: &mut Config) {
+    config.backend_name = String::from("mock_origin");
+    config.backend_host = HeaderValue::from_static("localhost");
+    config.hooks = Box::new(MockOrigin);
+}
+
+struct MockOrigin;
+
+impl Hooks for MockOrigin {
+    fn on_fragment_dispatch(&self, bereq: &mut Request) {
+        bereq.set_header(MOCK_HEADER, "1");
+    }
+}
+
+/// Answers an origin request sent by a service configured with
+/// `configure_mock_origin`. Returns false for client requests.
+///
+/// Byte `i` of the object is `i % 251`, so misplaced fragments show up at
+/// any block size. Query parameters shape the object and inject failures:
+/// `size` (default 4 MiB), `truncate_at` ends any body covering that offset
+/// there, `fail_at` answers the fragment covering that offset with
+/// `fail_status` (default 503), `change_at` gives fragments from that offset
+/// on another etag, `ignore_range` answers with the whole object,
+/// `unknown_length` sends `*` as the complete length and `no_head` answers
+/// `HEAD` with a `405`.
+pub fn serve_mock_origin(req: &Request) -> bool {
+    if !req.contains_header(MOCK_HEADER) {
+        return false;
+    }
+    if *req.get_method() == Method::HEAD && req.get_query_parameter("no_head").is_some() {
+        Response::from_status(StatusCode::METHOD_NOT_ALLOWED).send_to_client();
+        return true;
+    }
+    let param = |name| req.get_query_parameter(name).and_then(parse_digits);
+    let size = param("size").filter(|&size| size > 0).unwrap_or(4 << 20);
+    let etag = |first: usize| match param("change_at") {
+        Some(offset) if first >= offset => format!("\"mock-{}-changed\"", size),
+        _ => format!("\"mock-{}\"", size),
+    };
+    let values = req.get_header_all_str("range");
+    let req_range = match RequestRange::parse_header(&values, true, true) {
+        Ok(req_range) if req.get_query_parameter("ignore_range").is_none() => req_range,
+        Ok(_) => None,
+        Err(_) => {
+            Response::from_status(StatusCode::BAD_REQUEST).send_to_client();
+            return true;
+        }
+    };
+    let range = match ResolvedRange::new(&req_range, size) {
+        Some(range) => range,
+        _ => {
+            Response::from_status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .with_header("content-range", format!("bytes */{}", size))
+                .send_to_client();
+            return true;
+        }
+    };
+    if matches!(param("fail_at"), Some(offset) if (range.first..=range.last).contains(&offset)) {
+        let status = param("fail_status")
+            .and_then(|status| StatusCode::from_u16(status as u16).ok())
+            .unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+        Response::from_status(status).send_to_client();
+        return true;
+    }
+    let mut resp = Response::from_status(StatusCode::OK)
+        .with_header("content-type", "application/octet-stream")
+        .with_header("etag", etag(range.first))
+        .with_header("accept-ranges", "bytes")
+        .with_header("content-length", (range.last - range.first + 1).to_string());
+    if req_range.is_some() {
+        let complete_length = match req.get_query_parameter("unknown_length") {
+            Some(_) => String::from("*"),
+            None => size.to_string(),
+        };
+        resp.set_status(StatusCode::PARTIAL_CONTENT);
+        resp.set_header(
+            "content-range",
+            format!("bytes {}-{}/{}", range.first, range.last, complete_length),
+        );
+    }
+    let end = match param("truncate_at") {
+        Some(offset) if (range.first..=range.last).contains(&offset) => offset,
+        _ => range.last + 1,
+    };
+    let mut body = resp.stream_to_client();
+    if *req.get_method() != Method::HEAD {
+        let content = (range.first..end)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        if body.write_all(&content).is_err() {
+            return true;
+        }
+    }
+    // A truncated body is dropped unfinished, which aborts it like a broken
+    // origin connection would.
+    if end > range.last {
+        let _ = body.finish();
+    }
+    true
+}
@@ -0,0 +1,151 @@
+use crate::archive::ArchiveReader;
+use crate::config::Config;
+use crate::error::SegmentedCacheError as Error;
+use crate::log::{random_u64, with_log};
+use crate::range::{RequestRange, ResolvedRange};
+use crate::response::{range_not_satisfiable, set_response_headers};
+use crate::trace::TraceContext;
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use std::cmp::{max, min};
+use std::io::Write;
+
+/// The ranges of a `GET` to serve as `multipart/byteranges`: more than one,
+/// no more than `Config::multipart_ranges`, and not merged into one.
+pub(crate) fn multipart_ranges(req: &Request, config: &Config) -> Option<Vec<RequestRange>> {
+    if config.multipart_ranges < 2 || *req.get_method() != Method::GET || config.encrypted() {
+        return None;
+    }
+    if RequestRange::new(req, config).is_ok() {
+        return None;
+    }
+    let values = req
+        .get_header_all("range")
+        .map(|value| value.to_str())
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    let specs = RequestRange::parse_specs(&values).ok()?;
+    (specs.len() > 1 && specs.len() <= config.multipart_ranges).then_some(specs)
+}
+
+/// Serves each range as a part of a `multipart/byteranges` response. Chatty
+/// clients such as filesystem drivers ask for many small ranges at once,
+/// often several within one block, so the blocks are read through an
+/// `ArchiveReader`, which keeps the last few and fetches each only once,
+/// with the next `Config::parallelism` in flight.
+pub(crate) fn multipart_response(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    parts: Vec<RequestRange>,
+    resp_header_sent: &mut bool,
+    resp_headers: &[(String, String)],
+) -> Result<Option<Response>, Error> {
+    let mut reader = ArchiveReader::open(req, config, trace)?;
+    let complete_length = reader.complete_length;
+    let mut satisfiable: Vec<ResolvedRange> = parts
+        .into_iter()
+        .filter_map(|part| ResolvedRange::new(&Some(part), complete_length))
+        .collect();
+    if satisfiable.is_empty() {
+        return Ok(Some(range_not_satisfiable(complete_length)));
+    }
+    // Parts are sent in order of offset, and ranges closer together than
+    // `Config::multipart_coalesce_gap` share one, as RFC 9110 allows.
+    satisfiable.sort_by_key(|range| range.first);
+    let mut ranges: Vec<ResolvedRange> = Vec::with_capacity(satisfiable.len());
+    for range in satisfiable {
+        match ranges.last_mut() {
+            Some(last)
+                if range.first <= last.last.saturating_add(config.multipart_coalesce_gap + 1) =>
+            {
+                last.last = max(last.last, range.last);
+            }
+            _ => ranges.push(range),
+        }
+    }
+    let block_size = config.block_size;
+    let mut blocks: Vec<usize> = Vec::new();
+    for range in &ranges {
+        for block in range.first / block_size..=range.last / block_size {
+            if blocks.last() != Some(&(block * block_size)) {
+                blocks.push(block * block_size);
+            }
+        }
+    }
+
+    let boundary = format!("{:016x}{:016x}", random_u64(), random_u64());
+    let content_type = reader
+        .content_type
+        .clone()
+        .or_else(|| config.fallback_content_type.clone())
+        .unwrap_or_else(|| String::from("application/octet-stream"));
+    let part_headers: Vec<String> = ranges
+        .iter()
+        .map(|range| {
+            format!(
+                "\r\n--{}\r\ncontent-type: {}\r\ncontent-range: bytes {}-{}/{}\r\n\r\n",
+                boundary, content_type, range.first, range.last, complete_length
+            )
+        })
+        .collect();
+    let trailer = format!("\r\n--{}--\r\n", boundary);
+    let content_length = part_headers.iter().map(String::len).sum::<usize>()
+        + ranges
+            .iter()
+            .map(|range| range.last - range.first + 1)
+            .sum::<usize>()
+        + trailer.len();
+
+    let mut resp = Response::from_status(StatusCode::PARTIAL_CONTENT);
+    resp.set_header(
+        "content-type",
+        format!("multipart/byteranges; boundary={}", boundary),
+    );
+    resp.set_header("content-length", content_length.to_string());
+    resp.set_header("accept-ranges", "bytes");
+    if let Some(etag) = &reader.validators.etag {
+        resp.set_header("etag", etag);
+    }
+    if let Some(last_modified) = &reader.validators.last_modified {
+        resp.set_header("last-modified", last_modified);
+    }
+    resp.set_header("x-request-id", with_log(|log| log.request_id.clone()));
+    set_response_headers(&mut resp, resp_headers);
+    config.hooks.on_response_headers(&mut resp);
+    with_log(|log| log.status = Some(resp.get_status()));
+    let mut resp_body = resp.stream_to_client();
+    *resp_header_sent = true;
+
+    let mut sent = 0;
+    let mut next_block = 0;
+    for (range, part_header) in ranges.iter().zip(&part_headers) {
+        resp_body
+            .write_all(part_header.as_bytes())
+            .map_err(|_| Error::ClientGone)?;
+        let mut position = range.first;
+        while position <= range.last {
+            // Blocks are read in order, so the ones after the current one
+            // are requested while it is sent.
+            let block_first = position / block_size * block_size;
+            while blocks
+                .get(next_block)
+                .is_some_and(|&first| first < block_first)
+            {
+                next_block += 1;
+            }
+            reader.prefetch(&blocks[next_block..])?;
+            let len = min(range.last + 1, block_first + block_size) - position;
+            let bytes = reader.read(position, len)?;
+            resp_body.write_all(&bytes).map_err(|_| Error::ClientGone)?;
+            position += len;
+            sent += len;
+            with_log(|log| log.bytes_sent = sent);
+        }
+    }
+    resp_body
+        .write_all(trailer.as_bytes())
+        .map_err(|_| Error::ClientGone)?;
+    let _ = resp_body.finish();
+    Ok(None)
+}
@@ -0,0 +1,132 @@
+use fastly::secret_store::SecretStore;
+use fastly::Request;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The hash of an empty payload, since origin requests never have a body.
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+/// A bucket in Fastly Object Storage, or another S3-compatible store, used as
+/// the origin. The backend points at the storage endpoint, and origin
+/// requests are addressed path-style and signed with AWS Signature V4.
+pub struct ObjectStorage {
+    pub bucket: String,
+    /// The region the endpoint belongs to, such as `us-east`.
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+impl ObjectStorage {
+    /// Reads the keys from the `access_key_id` and `secret_access_key`
+    /// entries of a Secret Store.
+    pub fn from_secret_store(store: &str, bucket: &str, region: &str) -> Option<Self> {
+        let store = SecretStore::open(store).ok()?;
+        let secret = |name| {
+            let plaintext = store.get(name)?.plaintext();
+            String::from_utf8(plaintext.to_vec()).ok()
+        };
+        Some(ObjectStorage {
+            bucket: String::from(bucket),
+            region: String::from(region),
+            access_key_id: secret("access_key_id")?,
+            secret_access_key: secret("secret_access_key")?,
+        })
+    }
+
+    /// Rewrites an origin request for the bucket and signs it, so it has to
+    /// be the last change made to the request.
+    pub(crate) fn sign(&self, bereq: &mut Request, host: &str) {
+        // Query parameters would address S3 subresources, not the object.
+        bereq.remove_query();
+        let path = format!("/{}{}", self.bucket, canonical_path(bereq.get_path()));
+        bereq.set_path(&path);
+        let (date, time) = timestamp(SystemTime::now());
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            bereq.get_method(),
+            path,
+            host,
+            EMPTY_SHA256,
+            time,
+            SIGNED_HEADERS,
+            EMPTY_SHA256
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            time,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = [date.as_str(), &self.region, "s3", "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", self.secret_access_key).into_bytes(),
+                |key, part| hmac(&key, part),
+            );
+        bereq.set_header("x-amz-content-sha256", EMPTY_SHA256);
+        bereq.set_header("x-amz-date", &time);
+        bereq.set_header(
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key_id,
+                scope,
+                SIGNED_HEADERS,
+                hex(&hmac(&key, &string_to_sign))
+            ),
+        );
+    }
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Percent-encodes what the client sent unencoded, leaving escapes alone so
+/// that nothing is encoded twice.
+fn canonical_path(path: &str) -> String {
+    path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b'%' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// The UTC date and the date and time in the basic ISO 8601 format SigV4
+/// wants.
+fn timestamp(now: SystemTime) -> (String, String) {
+    let secs = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Converts days since the epoch to a civil date, in 400-year eras.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let time = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+    (date, time)
+}
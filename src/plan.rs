@@ -0,0 +1,90 @@
+use crate::config::Config;
+use crate::log::JsonRecord;
+use crate::range::{parse_digits, RequestRange, ResolvedRange};
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+use std::cmp::{max, min};
+
+pub(crate) const PLAN_PATH: &str = "/__sc/plan";
+
+/// Describes the fragments a request for `url` and `range` would be served
+/// from, without sending anything to the origin. An open or missing range
+/// needs the object's `length`.
+pub(crate) fn plan_response(req: &Request, config: &Config) -> Response {
+    match plan(req, config) {
+        Ok(plan) => Response::from_status(StatusCode::OK)
+            .with_header("content-type", "application/json")
+            .with_header("cache-control", "no-store")
+            .with_body(plan),
+        Err(e) => Response::from_status(StatusCode::BAD_REQUEST)
+            .with_header("content-type", "application/json")
+            .with_body(JsonRecord::new().str("error", e).finish()),
+    }
+}
+
+fn plan(req: &Request, config: &Config) -> Result<String, &'static str> {
+    let url = req.get_query_parameter("url").ok_or("missing url")?;
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let req_range = match req.get_query_parameter("range") {
+        Some(range) => RequestRange::parse_header(
+            &[range],
+            config.merge_identical_ranges,
+            config.merge_overlapping_ranges,
+        )?,
+        None => None,
+    };
+    let length = match req.get_query_parameter("length") {
+        Some(length) => Some(parse_digits(length).ok_or("invalid length")?),
+        None => None,
+    };
+    let range = match (length, req_range.and_then(|range| range.get_last())) {
+        (Some(length), _) => {
+            ResolvedRange::new(&req_range, length).ok_or("range not satisfiable")?
+        }
+        (None, Some(last)) => ResolvedRange {
+            first: req_range.map_or(0, |range| range.get_first()),
+            last,
+        },
+        (None, None) => return Err("length needed for an open range"),
+    };
+    let single = config.parallelism == 1 && !config.caching_enabled(path);
+    let (first, last) = if single {
+        (range.first, range.last)
+    } else {
+        let first = range.first / config.block_size * config.block_size;
+        (first, first + config.block_size - 1)
+    };
+    let mut blocks = vec![(first, last)];
+    let mut position = last + 1;
+    while position <= range.last {
+        blocks.push((position, position + config.block_size - 1));
+        position += config.block_size;
+    }
+    let fragments = blocks
+        .iter()
+        .map(|&(first, last)| {
+            JsonRecord::new()
+                .str("range", &format!("bytes={}-{}", first, last))
+                .num("first", first)
+                .num("last", last)
+                .num("size", last - first + 1)
+                .num(
+                    "bytes_used",
+                    min(last, range.last) - max(first, range.first) + 1,
+                )
+                .finish()
+        })
+        .collect::<Vec<_>>();
+    Ok(JsonRecord::new()
+        .str("url", url)
+        .num("first", range.first)
+        .num("last", range.last)
+        .num("block_size", config.block_size)
+        .num("parallelism", config.max_in_flight())
+        .str("backend", &config.backend_name)
+        .num("cached", config.caching_enabled(path))
+        .num("fragments", fragments.len())
+        .num("too_many_fragments", fragments.len() > config.max_fragments)
+        .raw("plan", &format!("[{}]", fragments.join(",")))
+        .finish())
+}
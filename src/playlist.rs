@@ -0,0 +1,97 @@
+use crate::config::{path_extension, Config};
+use crate::error::SegmentedCacheError as Error;
+use crate::fetch::origin_request;
+use crate::trace::TraceContext;
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+
+/// HLS and DASH manifests are small and change under live streams, so they
+/// are fetched whole rather than segmented.
+pub(crate) fn is_playlist(path: &str) -> bool {
+    path_extension(path)
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("m3u8") || ext.eq_ignore_ascii_case("mpd"))
+}
+
+/// The origin's response for a playlist, with `Link` preload hints for
+/// the first `Config::playlist_preload_segments` segments it references, so
+/// players start without waiting on the playlist to name them.
+pub(crate) fn playlist_response(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+) -> Result<Response, Error> {
+    let (bereq, _) = origin_request(req, config, trace, req.get_method().clone());
+    let mut beresp = bereq
+        .send(&config.backend_name)
+        .map_err(|e| Error::send("playlist backend request send", e))?;
+    if beresp.get_status() != StatusCode::OK
+        || *req.get_method() != Method::GET
+        || config.playlist_preload_segments == 0
+    {
+        return Ok(beresp);
+    }
+    let body = beresp.take_body_bytes();
+    if let Ok(text) = std::str::from_utf8(&body) {
+        let uris = if text.trim_start().starts_with("#EXTM3U") {
+            hls_segments(text)
+        } else {
+            dash_segments(text)
+        };
+        for uri in uris
+            .into_iter()
+            .filter(|uri| !uri.is_empty() && !uri.contains(['<', '>', ' ', ',', ';']))
+            .take(config.playlist_preload_segments)
+        {
+            beresp.append_header("link", format!("<{}>; rel=preload; as=fetch", uri));
+        }
+    }
+    beresp.set_body(body);
+    Ok(beresp)
+}
+
+/// The URIs of an HLS playlist in order, initialization sections included.
+/// A multivariant playlist lists its variant playlists.
+fn hls_segments(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter_map(|line| match line.strip_prefix("#EXT-X-MAP:") {
+            Some(map) => attribute(map, "URI"),
+            None if line.is_empty() || line.starts_with('#') => None,
+            None => Some(String::from(line)),
+        })
+        .collect()
+}
+
+/// The initialization and media URLs of a DASH `SegmentList`. Segment
+/// templates are left to the player.
+fn dash_segments(text: &str) -> Vec<String> {
+    text.split('<')
+        .filter_map(|tag| {
+            if tag.starts_with("Initialization") {
+                attribute(tag, "sourceURL")
+            } else if tag.starts_with("SegmentURL") {
+                attribute(tag, "media")
+            } else {
+                None
+            }
+        })
+        .map(|uri| uri.replace("&amp;", "&"))
+        .collect()
+}
+
+/// A quoted `name="value"` attribute, as in both HLS tags and XML.
+fn attribute(text: &str, name: &str) -> Option<String> {
+    let mut rest = text;
+    while let Some(start) = rest.find(name) {
+        let before = rest[..start].chars().next_back();
+        rest = &rest[start + name.len()..];
+        if before.is_some_and(|c| c.is_ascii_alphanumeric() || c == '-') {
+            continue;
+        }
+        if let Some(value) = rest.trim_start().strip_prefix('=') {
+            let value = value.trim_start().strip_prefix('"')?;
+            return value.split_once('"').map(|(value, _)| String::from(value));
+        }
+    }
+    None
+}
@@ -0,0 +1,59 @@
+use crate::config::Config;
+use crate::fetch::origin_request;
+use crate::response::{accepts_encoding, compressible};
+use crate::trace::TraceContext;
+use fastly::http::Method;
+use fastly::{Request, Response};
+
+/// The representation picked for an object that may have precompressed
+/// siblings at the origin.
+pub(crate) enum Precompressed {
+    /// The sibling with this `Content-Encoding` is served in its place.
+    Sibling(String),
+    Identity,
+}
+
+/// Points `req` at the first of `Config::precompressed_siblings` the client
+/// accepts and the origin has, checked with a `HEAD`. Only objects of
+/// compressible content types are considered.
+pub(crate) fn select_precompressed(
+    req: &mut Request,
+    config: &Config,
+    trace: &TraceContext,
+) -> Option<Precompressed> {
+    if config.precompressed_siblings.is_empty()
+        || !compressible(config, config.fallback_content_type.as_deref())
+    {
+        return None;
+    }
+    for (encoding, suffix) in &config.precompressed_siblings {
+        if !accepts_encoding(req, encoding) {
+            continue;
+        }
+        let mut sibling = req.clone_without_body();
+        sibling.set_path(&format!("{}{}", req.get_path(), suffix));
+        let (bereq, _) = origin_request(&sibling, config, trace, Method::HEAD);
+        if bereq
+            .send(&config.backend_name)
+            .is_ok_and(|beresp| beresp.get_status().is_success())
+        {
+            req.set_path(sibling.get_path());
+            return Some(Precompressed::Sibling(encoding.clone()));
+        }
+    }
+    Some(Precompressed::Identity)
+}
+
+impl Precompressed {
+    /// Labels a sibling's bytes with its encoding and the object's own
+    /// content type. Either way the response varies with `Accept-Encoding`.
+    pub(crate) fn set_headers(&self, resp: &mut Response, config: &Config) {
+        if let Precompressed::Sibling(encoding) = self {
+            resp.set_header("content-encoding", encoding);
+            if let Some(content_type) = &config.fallback_content_type {
+                resp.set_header("content-type", content_type);
+            }
+        }
+        resp.append_header("vary", "accept-encoding");
+    }
+}
@@ -0,0 +1,557 @@
+use crate::budget::Permit;
+use crate::config::{path_extension, wildcard_match, Config};
+use crate::content_range::ContentRange;
+use crate::error::SegmentedCacheError as Error;
+use crate::fetch::{
+    content_digest, fragment_from_response, fragment_request, origin_request, FetchInfo,
+    FragReqGen, FragmentFetcher, CONTENT_DIGEST_HEADER,
+};
+use crate::headers::{cache_hit, header_str};
+use crate::json::JsonValue;
+use crate::kv::FragmentStore;
+use crate::log::{millis, with_log, JsonRecord};
+use crate::range::{parse_digits, RequestRange, ResolvedRange};
+use crate::stream::{Fragment, Validators};
+use crate::trace::TraceContext;
+use fastly::http::request::PendingRequest;
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use std::cmp::min;
+use std::io::Write;
+use std::time::Instant;
+
+pub(crate) const PREWARM_PATH: &str = "/__sc/prewarm";
+
+/// Fetches every block of `url`, or of the blocks covering its `range`,
+/// through the caches in front of the origin, so that a release doesn't
+/// start with all of them missing. Without a `url`, every object listed in
+/// the `manifest` object, or in `Config::prewarm_manifest`, is warmed in
+/// turn. The response streams one JSON line per block as it completes and a
+/// summary line per object. Only errors in the request itself, or in
+/// fetching the manifest, are returned as a response rather than streamed.
+pub(crate) fn prewarm_response(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+) -> Option<Response> {
+    if *req.get_method() != Method::POST {
+        return Some(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_header("allow", "POST"),
+        );
+    }
+    let entries = match req.get_query_parameter("url") {
+        Some(url) => vec![PrewarmEntry {
+            url: String::from(url),
+            size: None,
+        }],
+        None => match req
+            .get_query_parameter("manifest")
+            .or(config.prewarm_manifest.as_deref())
+        {
+            Some(manifest) => match prewarm_list(req, config, trace, manifest) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    let mut resp = bad_request(&e.to_string());
+                    resp.set_status(e.status());
+                    return Some(resp);
+                }
+            },
+            None => return Some(bad_request("missing url")),
+        },
+    };
+    let req_range = match req.get_query_parameter("range").map(|range| {
+        RequestRange::parse_header(
+            &[range],
+            config.merge_identical_ranges,
+            config.merge_overlapping_ranges,
+        )
+    }) {
+        Some(Ok(req_range)) => req_range,
+        Some(Err(e)) => return Some(bad_request(e)),
+        None => None,
+    };
+    // Every object takes at least one block.
+    if entries.len() > config.prewarm_max_blocks {
+        let mut resp = bad_request("more objects than prewarm_max_blocks");
+        resp.set_status(StatusCode::PAYLOAD_TOO_LARGE);
+        return Some(resp);
+    }
+    let mut remaining = config.prewarm_max_blocks;
+    let mut resp_body = Response::from_status(StatusCode::OK)
+        .with_header("content-type", "application/x-ndjson")
+        .with_header("cache-control", "no-store")
+        .stream_to_client();
+    for entry in &entries {
+        let summary = prewarm(
+            req,
+            config,
+            trace,
+            entry,
+            &req_range,
+            &mut remaining,
+            &mut resp_body,
+        );
+        if writeln!(resp_body, "{}", summary).is_err() {
+            break;
+        }
+    }
+    let _ = resp_body.finish();
+    None
+}
+
+pub(crate) struct PrewarmEntry {
+    pub(crate) url: String,
+    pub(crate) size: Option<usize>,
+}
+
+fn prewarm_list(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    manifest: &str,
+) -> Result<Vec<PrewarmEntry>, Error> {
+    let (bereq, _) = origin_request(&object_request(req, manifest), config, trace, Method::GET);
+    let mut beresp = bereq
+        .send(&config.backend_name)
+        .map_err(|e| Error::send("prewarm manifest request send", e))?;
+    if beresp.get_status() != StatusCode::OK {
+        return Err(Error::OriginStatus(beresp.get_status()).context("prewarm manifest"));
+    }
+    let body =
+        String::from_utf8(beresp.take_body_bytes()).map_err(|_| Error::Manifest("not UTF-8"))?;
+    parse_prewarm_list(&body).map_err(Error::Manifest)
+}
+
+/// Parses a JSON array whose items are either URLs or objects with a `url`
+/// string and an optional `size` number. Other members are ignored.
+pub(crate) fn parse_prewarm_list(text: &str) -> Result<Vec<PrewarmEntry>, &'static str> {
+    let items = match JsonValue::parse(text)? {
+        JsonValue::Array(items) => items,
+        _ => return Err("not a JSON array"),
+    };
+    items
+        .iter()
+        .map(|item| match item {
+            JsonValue::String(url) => Ok(PrewarmEntry {
+                url: url.clone(),
+                size: None,
+            }),
+            JsonValue::Object(_) => Ok(PrewarmEntry {
+                url: item
+                    .get("url")
+                    .and_then(JsonValue::as_str)
+                    .ok_or("list item without a url")?
+                    .to_string(),
+                size: match item.get("size") {
+                    Some(JsonValue::Number(size)) => {
+                        Some(parse_digits(size).ok_or("invalid size")?)
+                    }
+                    Some(_) => return Err("invalid size"),
+                    None => None,
+                },
+            }),
+            _ => Err("list items must be strings or objects"),
+        })
+        .collect()
+}
+
+fn bad_request(e: &str) -> Response {
+    Response::from_status(StatusCode::BAD_REQUEST)
+        .with_header("content-type", "application/json")
+        .with_body(JsonRecord::new().str("error", e).finish())
+}
+
+#[derive(Default)]
+struct Totals {
+    complete_length: Option<usize>,
+    fragments: usize,
+    cache_hits: usize,
+    bytes: usize,
+    /// Blocks still to be fetched within `Config::prewarm_max_blocks`.
+    remaining: usize,
+    /// Blocks were left out for `Config::prewarm_max_blocks`.
+    truncated: bool,
+}
+
+/// Warms one object, writing a line per block to `out`, and returns the
+/// summary line. A `size` that doesn't match the object is an error. No
+/// more than `remaining` blocks are fetched, and each one is taken off it.
+pub(crate) fn prewarm(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    entry: &PrewarmEntry,
+    req_range: &Option<RequestRange>,
+    remaining: &mut usize,
+    out: &mut impl Write,
+) -> String {
+    let start = Instant::now();
+    let mut totals = Totals {
+        remaining: *remaining,
+        ..Totals::default()
+    };
+    let result = warm(
+        &object_request(req, &entry.url),
+        config,
+        trace,
+        entry.size,
+        req_range,
+        out,
+        &mut totals,
+    );
+    *remaining = totals.remaining;
+    let url = entry.url.as_str();
+    let error = result.err().map(|e| e.to_string());
+    let mut record = JsonRecord::new();
+    record
+        .str("url", url)
+        .num("ok", error.is_none())
+        .opt_str("error", error.as_deref());
+    if let Some(length) = totals.complete_length {
+        record.num("complete_length", length);
+    }
+    record
+        .num("fragments", totals.fragments)
+        .num("cache_hits", totals.cache_hits)
+        .num("bytes", totals.bytes)
+        .num("truncated", totals.truncated)
+        .raw("ms", &millis(start.elapsed()))
+        .finish()
+}
+
+pub(crate) fn object_request(req: &Request, url: &str) -> Request {
+    let mut object = req.clone_without_body();
+    object.set_method(Method::GET);
+    object.remove_header("content-length");
+    object.remove_header("content-type");
+    match url.split_once('?') {
+        Some((path, query)) => {
+            object.set_path(path);
+            object.set_query_str(query);
+        }
+        None => {
+            object.set_path(url);
+            object.remove_query();
+        }
+    }
+    object
+}
+
+fn warm(
+    object: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    size: Option<usize>,
+    req_range: &Option<RequestRange>,
+    out: &mut impl Write,
+    totals: &mut Totals,
+) -> Result<(), Error> {
+    if totals.remaining == 0 {
+        totals.truncated = true;
+        return Ok(());
+    }
+    let first = req_range.as_ref().map_or(0, RequestRange::get_first);
+    let first = first / config.block_size * config.block_size;
+    let range = format!("bytes={}-{}", first, first + config.block_size - 1);
+    let (bereq, span_id) = fragment_request(object, config, trace, Method::GET, &range);
+    let sent = Instant::now();
+    let beresp = bereq
+        .send(&config.backend_name)
+        .map_err(|e| Error::send("prewarm backend request send", e))?;
+    let info = FetchInfo {
+        range,
+        span_id,
+        duration: sent.elapsed(),
+    };
+    if beresp.get_status() != StatusCode::PARTIAL_CONTENT {
+        return Err(Error::OriginStatus(beresp.get_status()));
+    }
+    let content_range = ContentRange::new(&beresp, None, config)?;
+    let complete_length = content_range.complete_length;
+    totals.complete_length = Some(complete_length);
+    if let Some(size) = size.filter(|&size| size != complete_length) {
+        return Err(Error::Inconsistency(format!(
+            "complete length {} rather than the listed {}",
+            complete_length, size
+        )));
+    }
+    let range = ResolvedRange::new(req_range, complete_length)
+        .ok_or(Error::RangeParse("range not satisfiable"))?;
+    let hit = cache_hit(&beresp);
+    let digest = content_digest(config, &beresp);
+    let store = FragmentStore::open(object, config, &Validators::new(&beresp), complete_length)
+        .map(|store| match &digest {
+            Some(digest) => store.by_digest(digest),
+            None => store,
+        });
+    let beresp = match &store {
+        Some(store) => store.save(content_range.first, beresp),
+        None => beresp,
+    };
+    let frag1 = Fragment::new(beresp, &content_range);
+    let validators = frag1.validators.clone();
+    totals.remaining -= 1;
+    drain(frag1, hit, &info, out, totals)?;
+
+    // Blocks past the budget are left for another prewarm.
+    let last = min(
+        range.last,
+        content_range
+            .last
+            .saturating_add(totals.remaining.saturating_mul(config.block_size)),
+    );
+    totals.truncated = last < range.last;
+    // The fetcher keeps the configured number of blocks in flight.
+    let mut fetcher = FragmentFetcher::new(
+        object,
+        config,
+        trace,
+        FragReqGen::new(content_range.last + 1, last, config),
+    );
+    fetcher.store = store;
+    fetcher.share_by_digest(digest.as_deref());
+    fetcher.dispatch()?;
+    while let Some((beresp, info)) = fetcher.next_response()? {
+        let hit = cache_hit(&beresp);
+        let frag = fragment_from_response(beresp, &info, complete_length, config)?;
+        if !frag.validators.matches(&validators) {
+            return Err(Error::Inconsistency(format!(
+                "object changed during prewarm at {}",
+                info.range
+            )));
+        }
+        totals.remaining = totals.remaining.saturating_sub(1);
+        drain(frag, hit, &info, out, totals)?;
+    }
+    Ok(())
+}
+
+fn drain(
+    mut frag: Fragment,
+    hit: Option<bool>,
+    info: &FetchInfo,
+    out: &mut impl Write,
+    totals: &mut Totals,
+) -> Result<(), Error> {
+    let bytes = std::io::copy(&mut frag.body, &mut std::io::sink()).map_err(Error::Read)? as usize;
+    totals.fragments += 1;
+    totals.bytes += bytes;
+    if hit == Some(true) {
+        totals.cache_hits += 1;
+    }
+    let line = JsonRecord::new()
+        .str("range", &info.range)
+        .num("bytes", bytes)
+        .opt_str("cache", hit.map(|hit| if hit { "hit" } else { "miss" }))
+        .raw("ms", &millis(info.duration))
+        .finish();
+    writeln!(out, "{}", line)
+        .and_then(|_| out.flush())
+        .map_err(|_| Error::ClientGone)?;
+    if bytes < frag.range_last - frag.first + 1 {
+        return Err(Error::Truncated {
+            offset: frag.first + bytes,
+        });
+    }
+    Ok(())
+}
+
+/// Fetches up to `Config::background_fill_blocks` blocks from `position` on,
+/// after the client's response is complete, so that its follow-up range
+/// requests find them cached. Stops at the first error or once
+/// `Config::background_fill_budget` is spent.
+pub(crate) fn background_fill(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    position: usize,
+    complete_length: usize,
+    validators: &Validators,
+    content_digest: Option<&str>,
+) {
+    // The block holding `position` was fetched whole already.
+    let first = position.div_ceil(config.block_size) * config.block_size;
+    if first >= complete_length {
+        return;
+    }
+    let last = min(
+        first + config.background_fill_blocks * config.block_size,
+        complete_length,
+    ) - 1;
+    let mut fetcher =
+        FragmentFetcher::new(req, config, trace, FragReqGen::new(first, last, config));
+    fetcher.store = FragmentStore::open(req, config, validators, complete_length);
+    fetcher.share_by_digest(content_digest);
+    let mut totals = Totals::default();
+    let _ = fill(&mut fetcher, complete_length, validators, &mut totals);
+    fetcher.cancel();
+    with_log(|log| log.background_fragments = totals.fragments);
+}
+
+fn fill(
+    fetcher: &mut FragmentFetcher,
+    complete_length: usize,
+    validators: &Validators,
+    totals: &mut Totals,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    fetcher.dispatch()?;
+    while let Some((beresp, info)) = fetcher.next_response()? {
+        if start.elapsed() > fetcher.config.background_fill_budget {
+            break;
+        }
+        let hit = cache_hit(&beresp);
+        let frag = fragment_from_response(beresp, &info, complete_length, fetcher.config)?;
+        if !frag.validators.matches(validators) {
+            break;
+        }
+        drain(frag, hit, &info, &mut std::io::sink(), totals)?;
+    }
+    Ok(())
+}
+
+/// How many blocks at the start and at the end of the object to request
+/// along with `range`, going by its readahead profile or else its type.
+/// Players read the `moov` atom of an MP4, found at one end or the other,
+/// before playing linearly. Range-aware ZIP readers jump from the head to
+/// the central directory at the end.
+pub(crate) fn edge_blocks(
+    req: &Request,
+    resp: &Response,
+    config: &Config,
+    range: &ResolvedRange,
+) -> (usize, usize) {
+    if let Some(profile) = config.readahead_profile(req.get_path()) {
+        return (profile.head_blocks, profile.tail_blocks);
+    }
+    let essence = header_str(resp.get_header("content-type"))
+        .and_then(|value| value.split(';').next())
+        .map_or("", str::trim);
+    let extension = path_extension(req.get_path()).unwrap_or("");
+    let any =
+        |names: &[&str], value: &str| names.iter().any(|name| value.eq_ignore_ascii_case(name));
+    if any(&["video/mp4", "audio/mp4", "video/quicktime"], essence) {
+        (config.mp4_head_blocks, config.mp4_tail_blocks)
+    } else if range.first < config.block_size
+        && (any(
+            &[
+                "application/zip",
+                "application/java-archive",
+                "application/vnd.android.package-archive",
+            ],
+            essence,
+        ) || any(&["zip", "jar", "apk"], extension))
+    {
+        (0, config.zip_tail_blocks)
+    } else {
+        (0, 0)
+    }
+}
+
+/// Requests for the `edges` blocks at each end of the object that `range`
+/// doesn't cover. They are sent while the response is streamed and drained
+/// by `drain_prefetched` once it is complete.
+pub(crate) fn prefetch_edges(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    range: &ResolvedRange,
+    complete_length: usize,
+    (head_blocks, tail_blocks): (usize, usize),
+    content_digest: Option<&str>,
+) -> Vec<(PendingRequest, Permit)> {
+    let block_size = config.block_size;
+    let blocks = complete_length.div_ceil(block_size);
+    let requested = range.first / block_size..=range.last / block_size;
+    let head = 0..min(head_blocks, blocks);
+    let tail = blocks.saturating_sub(tail_blocks).max(head.end)..blocks;
+    head.chain(tail)
+        .filter(|block| !requested.contains(block))
+        .filter_map(|block| {
+            let permit = Permit::spare(config)?;
+            let first = block * block_size;
+            let range = format!(
+                "bytes={}-{}",
+                first,
+                min(first + block_size, complete_length) - 1
+            );
+            let (mut bereq, _) = fragment_request(req, config, trace, Method::GET, &range);
+            if let Some(digest) = content_digest {
+                bereq.set_header(CONTENT_DIGEST_HEADER, digest);
+            }
+            let promise = bereq.send_async(&config.backend_name).ok()?;
+            Some((promise, permit))
+        })
+        .collect()
+}
+
+/// Requests for the first blocks of the object after `req`'s in the first
+/// of `Config::sequence_prewarm` its path matches.
+pub(crate) fn prefetch_next(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+) -> Vec<(PendingRequest, Permit)> {
+    let (path, blocks) = match config.sequence_prewarm.iter().find_map(|sequence| {
+        next_in_sequence(&sequence.pattern, req.get_path()).map(|path| (path, sequence.blocks))
+    }) {
+        Some(next) => next,
+        None => return Vec::new(),
+    };
+    let mut next = req.clone_without_body();
+    next.set_path(&path);
+    (0..blocks)
+        .filter_map(|block| {
+            let permit = Permit::spare(config)?;
+            let first = block * config.block_size;
+            let range = format!("bytes={}-{}", first, first + config.block_size - 1);
+            let (bereq, _) = fragment_request(&next, config, trace, Method::GET, &range);
+            let promise = bereq.send_async(&config.backend_name).ok()?;
+            Some((promise, permit))
+        })
+        .collect()
+}
+
+/// `path` with its number one higher, when it matches `pattern`. The last
+/// run of digits that fits the pattern is the number.
+fn next_in_sequence(pattern: &str, path: &str) -> Option<String> {
+    let (before, after) = pattern.split_once("{n}")?;
+    let bytes = path.as_bytes();
+    let mut end = path.len();
+    while end > 0 {
+        if !bytes[end - 1].is_ascii_digit() {
+            end -= 1;
+            continue;
+        }
+        let start = bytes[..end]
+            .iter()
+            .rposition(|b| !b.is_ascii_digit())
+            .map_or(0, |i| i + 1);
+        if wildcard_match(before, &path[..start]) && wildcard_match(after, &path[end..]) {
+            let n: u64 = path[start..end].parse().ok()?;
+            return Some(format!(
+                "{}{:0width$}{}",
+                &path[..start],
+                n.checked_add(1)?,
+                &path[end..],
+                width = end - start
+            ));
+        }
+        end = start;
+    }
+    None
+}
+
+pub(crate) fn drain_prefetched(pending: Vec<(PendingRequest, Permit)>) {
+    let mut fragments = 0;
+    for (promise, _permit) in pending {
+        if let Ok(mut beresp) = promise.wait() {
+            if beresp.get_status() == StatusCode::PARTIAL_CONTENT
+                && std::io::copy(&mut beresp.take_body(), &mut std::io::sink()).is_ok()
+            {
+                fragments += 1;
+            }
+        }
+    }
+    with_log(|log| log.background_fragments += fragments);
+}
@@ -0,0 +1,38 @@
+use crate::config::Config;
+use crate::kv::surrogate_key;
+use crate::log::JsonRecord;
+use crate::prewarm::object_request;
+use fastly::http::purge::soft_purge_surrogate_key;
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+
+pub(crate) const PURGE_PATH: &str = "/__sc/purge";
+
+/// Soft-purges every block of `url` by the surrogate key its fragment
+/// requests carry, so each one is marked stale and revalidated with the
+/// origin when next requested rather than evicted.
+pub(crate) fn purge_response(req: &Request, config: &Config) -> Response {
+    if *req.get_method() != Method::POST {
+        return Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_header("allow", "POST");
+    }
+    let url = match req.get_query_parameter("url") {
+        Some(url) => url,
+        None => return json_response(StatusCode::BAD_REQUEST, "error", "missing url"),
+    };
+    let key = surrogate_key(&object_request(req, url), config);
+    match soft_purge_surrogate_key(&key) {
+        Ok(()) => json_response(StatusCode::OK, "surrogate_key", &key),
+        Err(status) => json_response(
+            StatusCode::BAD_GATEWAY,
+            "error",
+            &format!("purge of {} failed: {:?}", key, status),
+        ),
+    }
+}
+
+fn json_response(status: StatusCode, name: &str, value: &str) -> Response {
+    Response::from_status(status)
+        .with_header("content-type", "application/json")
+        .with_header("cache-control", "no-store")
+        .with_body(JsonRecord::new().str(name, value).finish())
+}
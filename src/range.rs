@@ -0,0 +1,324 @@
+use crate::config::Config;
+use crate::error::SegmentedCacheError as Error;
+use fastly::Request;
+use std::cmp::min;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RequestRange {
+    Closed { first: usize, last: usize },
+    Open { first: usize },
+}
+
+impl RequestRange {
+    pub(crate) fn new(req: &Request, config: &Config) -> Result<Option<Self>, Error> {
+        let values = req
+            .get_header_all("range")
+            .map(|value| value.to_str())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| Error::RangeParse("invalid range header value"))?;
+        Self::parse_header(
+            &values,
+            config.merge_identical_ranges,
+            config.merge_overlapping_ranges,
+        )
+        .map_err(Error::RangeParse)
+    }
+
+    /// Parses the values of every `Range` field into the single range served.
+    pub(crate) fn parse_header(
+        values: &[&str],
+        merge_identical: bool,
+        merge_overlapping: bool,
+    ) -> Result<Option<Self>, &'static str> {
+        let mut specs = Self::parse_specs(values)?.into_iter();
+        let mut req_range = match specs.next() {
+            Some(req_range) => req_range,
+            None => return Ok(None),
+        };
+        for spec in specs {
+            req_range = req_range
+                .merge(spec, merge_identical, merge_overlapping)
+                .ok_or("conflicting range specs")?;
+        }
+        Ok(Some(req_range))
+    }
+
+    /// Every range spec of the `Range` fields, in order.
+    pub(crate) fn parse_specs(values: &[&str]) -> Result<Vec<Self>, &'static str> {
+        let mut specs = Vec::new();
+        for value in values {
+            let ranges = match value.split_once("=") {
+                Some((unit, ranges)) if unit.eq_ignore_ascii_case("bytes") => ranges,
+                _ => return Err("range not bytes"),
+            };
+            for spec in ranges
+                .split(",")
+                .map(str::trim)
+                .filter(|spec| !spec.is_empty())
+            {
+                specs.push(Self::parse(spec)?);
+            }
+        }
+        Ok(specs)
+    }
+
+    pub(crate) fn merge(
+        self,
+        other: Self,
+        merge_identical: bool,
+        merge_overlapping: bool,
+    ) -> Option<Self> {
+        if self == other && merge_identical {
+            return Some(self);
+        }
+        let end = |range: &Self| range.get_last().unwrap_or(usize::MAX);
+        let first = min(self.get_first(), other.get_first());
+        if !merge_overlapping
+            || self.get_first().max(other.get_first())
+                > min(end(&self), end(&other)).saturating_add(1)
+        {
+            return None;
+        }
+        Some(match (self.get_last(), other.get_last()) {
+            (Some(a), Some(b)) => RequestRange::Closed {
+                first,
+                last: a.max(b),
+            },
+            _ => RequestRange::Open { first },
+        })
+    }
+
+    pub(crate) fn parse(range: &str) -> Result<Self, &'static str> {
+        let req_range = match range.split_once("-") {
+            Some(("", last)) if last.len() > 0 => return Err("suffix range not supported"),
+            Some((first, "")) => {
+                let first = parse_digits(first).ok_or("invalid range lower bound")?;
+                RequestRange::Open { first }
+            }
+            Some((first, last)) => {
+                let first = parse_digits(first).ok_or("invalid range lower bound")?;
+                let last = parse_digits(last).ok_or("invalid range upper bound")?;
+                if last < first {
+                    return Err("range upper bound lower than lower bound");
+                }
+                RequestRange::Closed { first, last }
+            }
+            _ => return Err("cannot parse requested range"),
+        };
+        Ok(req_range)
+    }
+
+    pub(crate) fn get_first(&self) -> usize {
+        return match &self {
+            RequestRange::Closed { first, .. } | RequestRange::Open { first } => *first,
+        };
+    }
+
+    pub(crate) fn get_last(&self) -> Option<usize> {
+        return match &self {
+            RequestRange::Closed { last, .. } => Some(*last),
+            RequestRange::Open { .. } => None,
+        };
+    }
+}
+
+pub(crate) struct ResolvedRange {
+    pub(crate) first: usize,
+    pub(crate) last: usize,
+}
+
+impl ResolvedRange {
+    pub(crate) fn new(req_range: &Option<RequestRange>, complete_length: usize) -> Option<Self> {
+        if let Some(req_range) = req_range {
+            let first = req_range.get_first();
+            if first >= complete_length {
+                return None;
+            }
+            let last = req_range
+                .get_last()
+                .map(|x| min(x, complete_length - 1))
+                .unwrap_or(complete_length - 1);
+            Some(ResolvedRange { first, last })
+        } else {
+            Some(ResolvedRange {
+                first: 0,
+                last: complete_length - 1,
+            })
+        }
+    }
+}
+
+/// Parses a 1*DIGIT position, rejecting the signs `str::parse` accepts and
+/// values that overflow.
+pub(crate) fn parse_digits(value: &str) -> Option<usize> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn spec(range: &RequestRange) -> String {
+        match range {
+            RequestRange::Closed { first, last } => format!("{}-{}", first, last),
+            RequestRange::Open { first } => format!("{}-", first),
+        }
+    }
+
+    fn closed() -> impl Strategy<Value = RequestRange> {
+        (0..1usize << 40, 0..1usize << 20).prop_map(|(first, len)| RequestRange::Closed {
+            first,
+            last: first + len,
+        })
+    }
+
+    fn request_range() -> impl Strategy<Value = RequestRange> {
+        prop_oneof![
+            closed(),
+            (0..1usize << 40).prop_map(|first| RequestRange::Open { first }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn parse_round_trips(range in request_range()) {
+            prop_assert_eq!(RequestRange::parse(&spec(&range)), Ok(range));
+        }
+
+        #[test]
+        fn parse_rejects_out_of_order_bounds(first in 1..usize::MAX, below in 1..usize::MAX) {
+            let last = first.saturating_sub(below);
+            prop_assert!(RequestRange::parse(&format!("{}-{}", first, last)).is_err());
+        }
+
+        #[test]
+        fn parse_rejects_suffix_specs(length in 1..usize::MAX) {
+            prop_assert!(RequestRange::parse(&format!("-{}", length)).is_err());
+            let value = format!("bytes=-{}", length);
+            prop_assert!(RequestRange::parse_header(&[&value], true, true).is_err());
+        }
+
+        #[test]
+        fn parse_digits_accepts_every_usize(value in any::<usize>(), zeros in 0..4usize) {
+            let digits = format!("{}{}", "0".repeat(zeros), value);
+            prop_assert_eq!(parse_digits(&digits), Some(value));
+        }
+
+        #[test]
+        fn parse_digits_rejects_overflow(excess in 1..u128::MAX - usize::MAX as u128) {
+            let value = usize::MAX as u128 + excess;
+            prop_assert_eq!(parse_digits(&value.to_string()), None);
+        }
+
+        #[test]
+        fn parse_digits_rejects_signs_and_junk(value in any::<usize>(), junk in "[+\\- a-z.]") {
+            prop_assert_eq!(parse_digits(&format!("{}{}", junk, value)), None);
+            prop_assert_eq!(parse_digits(&format!("{}{}", value, junk)), None);
+        }
+
+        #[test]
+        fn parse_specs_keeps_every_spec_in_order(
+            ranges in prop::collection::vec(request_range(), 1..8),
+            split in 0..8usize,
+            unit in "[bB][yY][tT][eE][sS]",
+        ) {
+            let split = split.min(ranges.len());
+            let join = |ranges: &[RequestRange]| {
+                let specs = ranges.iter().map(spec).collect::<Vec<_>>().join(" , ");
+                format!("{}={}", unit, specs)
+            };
+            let values = [join(&ranges[..split]), join(&ranges[split..])];
+            let values = values.iter().map(String::as_str).collect::<Vec<_>>();
+            prop_assert_eq!(RequestRange::parse_specs(&values), Ok(ranges));
+        }
+
+        #[test]
+        fn parse_specs_rejects_other_units(range in request_range(), unit in "[a-z]{1,8}") {
+            prop_assume!(unit != "bytes");
+            let value = format!("{}={}", unit, spec(&range));
+            prop_assert!(RequestRange::parse_specs(&[&value]).is_err());
+        }
+
+        #[test]
+        fn merge_is_symmetric(a in request_range(), b in request_range()) {
+            prop_assert_eq!(a.merge(b, true, true), b.merge(a, true, true));
+            prop_assert_eq!(a.merge(b, false, true), b.merge(a, false, true));
+        }
+
+        #[test]
+        fn merge_identical_only_when_asked(range in request_range()) {
+            prop_assert_eq!(range.merge(range, true, false), Some(range));
+            prop_assert_eq!(range.merge(range, false, false), None);
+        }
+
+        #[test]
+        fn merge_overlapping_covers_both(a in closed(), b in closed()) {
+            let (first, last) = match (a, b) {
+                (
+                    RequestRange::Closed { first: a_first, last: a_last },
+                    RequestRange::Closed { first: b_first, last: b_last },
+                ) => (a_first.max(b_first), a_last.min(b_last)),
+                _ => unreachable!(),
+            };
+            let merged = a.merge(b, false, true);
+            if first > last + 1 {
+                prop_assert_eq!(merged, None);
+            } else {
+                prop_assert_eq!(
+                    merged,
+                    Some(RequestRange::Closed {
+                        first: a.get_first().min(b.get_first()),
+                        last: a.get_last().max(b.get_last()).unwrap(),
+                    })
+                );
+            }
+            if a != b {
+                prop_assert_eq!(a.merge(b, true, false), None);
+            }
+        }
+
+        #[test]
+        fn merge_open_ended_stays_open(a in request_range(), first in 0..1usize << 40) {
+            let open = RequestRange::Open { first };
+            let merged = a.merge(open, false, true);
+            if a.get_last().is_none_or(|last| last + 1 >= first) {
+                prop_assert_eq!(
+                    merged,
+                    Some(RequestRange::Open {
+                        first: first.min(a.get_first()),
+                    })
+                );
+            } else {
+                prop_assert_eq!(merged, None);
+            }
+        }
+
+        #[test]
+        fn parse_header_merges_repeated_values(range in request_range(), repeats in 1..4usize) {
+            let value = format!("bytes={}", spec(&range));
+            let values = vec![value.as_str(); repeats];
+            prop_assert_eq!(RequestRange::parse_header(&values, true, false), Ok(Some(range)));
+        }
+
+        #[test]
+        fn parse_header_rejects_disjoint_specs(a in closed(), gap in 2..1usize << 20, len in 0..1usize << 20) {
+            let first = a.get_last().unwrap() + gap;
+            let b = RequestRange::Closed { first, last: first + len };
+            let value = format!("bytes={},{}", spec(&a), spec(&b));
+            prop_assert!(RequestRange::parse_header(&[&value], true, true).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_header_without_specs_is_no_range() {
+        assert_eq!(RequestRange::parse_header(&[], true, true), Ok(None));
+        assert_eq!(
+            RequestRange::parse_header(&["bytes= , "], true, true),
+            Ok(None)
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use crate::headers::header_str;
+use fastly::erl::{Penaltybox, RateCounter, RateWindow, ERL};
+use fastly::Request;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+/// Per-client limits enforced with Fastly's edge rate limiter. Segmentation
+/// turns one client request into many origin requests, so ranged scraping
+/// costs far more than it looks. A client over either limit spends `penalty`
+/// in the penalty box, answered with 429s.
+pub struct RateLimit {
+    /// The rate counter and penalty box, as named in the service.
+    pub rate_counter: String,
+    pub penalty_box: String,
+    pub window: RateWindow,
+    /// Requests per second, averaged over `window`.
+    pub requests_per_second: u32,
+    /// KiB sent per second, averaged over `window`.
+    pub kib_per_second: Option<u32>,
+    pub penalty: Duration,
+    /// A header identifying the client, such as an API token. The client IP
+    /// is used when it is absent.
+    pub key_header: Option<String>,
+}
+
+impl RateLimit {
+    pub fn new(rate_counter: &str, penalty_box: &str) -> Self {
+        RateLimit {
+            rate_counter: String::from(rate_counter),
+            penalty_box: String::from(penalty_box),
+            window: RateWindow::TenSecs,
+            requests_per_second: 50,
+            kib_per_second: None,
+            penalty: Duration::from_secs(60),
+            key_header: None,
+        }
+    }
+
+    pub(crate) fn key(&self, req: &Request) -> String {
+        match self
+            .key_header
+            .as_deref()
+            .and_then(|name| header_str(req.get_header(name)))
+        {
+            Some(value) => format!("key:{}", value),
+            None => format!(
+                "ip:{}",
+                req.get_client_ip_addr()
+                    .map_or_else(String::new, |ip| ip.to_string())
+            ),
+        }
+    }
+
+    /// Counts the request and tells whether the client is over a limit.
+    /// Errors from the limiter let the request through.
+    pub(crate) fn exceeded(&self, key: &str) -> bool {
+        let penalty_box = Penaltybox::open(&self.penalty_box);
+        if let Some(limit) = self.kib_per_second {
+            let bytes_key = format!("{}:bytes", key);
+            let rate = RateCounter::open(&self.rate_counter).lookup_rate(&bytes_key, self.window);
+            if rate.is_ok_and(|rate| rate > limit) {
+                let _ = penalty_box.add(key, self.penalty);
+                return true;
+            }
+        }
+        let limiter = ERL::open(RateCounter::open(&self.rate_counter), penalty_box);
+        limiter
+            .check_rate(key, 1, self.window, self.requests_per_second, self.penalty)
+            .unwrap_or(false)
+    }
+
+    /// Counts the bytes sent to the client against its byte limit.
+    pub(crate) fn sent(&self, key: &str, bytes: usize) {
+        if self.kib_per_second.is_some() && bytes > 0 {
+            let kib = u32::try_from(bytes.div_ceil(1024)).unwrap_or(u32::MAX);
+            let _ = RateCounter::open(&self.rate_counter).increment(&format!("{}:bytes", key), kib);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::config::Config;
+use crate::headers::{header_str, no_transform};
+use crate::range::{RequestRange, ResolvedRange};
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+
+pub fn response_headers(req: &Request, config: &Config) -> Vec<(String, String)> {
+    let mut headers = config.security_headers.clone();
+    if let Some(via) = &config.via {
+        headers.push((String::from("via"), via.clone()));
+    }
+    if let Some(origins) = &config.timing_allow_origin {
+        headers.push((String::from("timing-allow-origin"), origins.clone()));
+    }
+    if config.served_by {
+        if let Ok(pop) = std::env::var("FASTLY_POP") {
+            headers.push((String::from("x-served-by"), pop));
+        }
+    }
+    if let Some(origin) = config.cors_origin(req) {
+        if origin != "*" {
+            headers.push((String::from("vary"), String::from("origin")));
+        }
+        headers.push((String::from("access-control-allow-origin"), origin));
+        let mut expose = config.cors_expose_headers.clone();
+        if config.advertise_block_size {
+            expose.push_str(", x-sc-block-size");
+        }
+        headers.push((String::from("access-control-expose-headers"), expose));
+    }
+    headers
+}
+
+pub fn set_response_headers(resp: &mut Response, resp_headers: &[(String, String)]) {
+    // Origin responses passed through may already carry some of these.
+    for (name, value) in resp_headers {
+        match name.as_str() {
+            "vary" | "via" => resp.append_header(name.as_str(), value),
+            _ => resp.set_header(name.as_str(), value),
+        }
+    }
+}
+
+pub(crate) fn preflight_response(req: &Request, config: &Config) -> Option<Response> {
+    if *req.get_method() != Method::OPTIONS
+        || req.get_header("access-control-request-method").is_none()
+    {
+        return None;
+    }
+    let origin = config.cors_origin(req)?;
+    Some(
+        Response::from_status(StatusCode::NO_CONTENT)
+            .with_header("access-control-allow-origin", origin)
+            .with_header("access-control-allow-methods", &config.cors_allowed_methods)
+            .with_header("access-control-allow-headers", &config.cors_allowed_headers)
+            .with_header("access-control-max-age", config.cors_max_age.to_string())
+            .with_header("vary", "origin"),
+    )
+}
+
+pub(crate) fn hotlink_response(config: &Config) -> Response {
+    match &config.hotlink_redirect {
+        Some(location) => Response::from_status(StatusCode::FOUND)
+            .with_header("location", location)
+            .with_header("cache-control", "no-store"),
+        None => Response::from_status(StatusCode::FORBIDDEN)
+            .with_body_text_plain("Hotlinking not allowed\n"),
+    }
+}
+
+/// Hands a full response to Fastly's dynamic compression when the client
+/// accepts gzip or Brotli and the content type is worth compressing. The
+/// compressed length isn't known up front, so the response is chunked.
+pub(crate) fn set_compression_hint(req: &Request, resp: &mut Response, config: &Config) {
+    if !config.compress_full_responses
+        || resp.get_status() != StatusCode::OK
+        || resp.get_header("content-encoding").is_some()
+        || no_transform(resp)
+    {
+        return;
+    }
+    let accepted = accepts_encoding(req, "gzip") || accepts_encoding(req, "br");
+    if !accepted || !compressible(config, header_str(resp.get_header("content-type"))) {
+        return;
+    }
+    resp.set_header("x-compress-hint", "on");
+    resp.remove_header("content-length");
+    resp.append_header("vary", "accept-encoding");
+    // The encoded bytes are another representation.
+    let etag = header_str(resp.get_header("etag"))
+        .filter(|etag| !etag.starts_with("W/"))
+        .map(|etag| format!("W/{}", etag));
+    if let Some(etag) = etag {
+        resp.set_header("etag", etag);
+    }
+}
+
+/// Whether the client's `Accept-Encoding` lists `encoding` without `q=0`.
+pub(crate) fn accepts_encoding(req: &Request, encoding: &str) -> bool {
+    header_str(req.get_header("accept-encoding")).is_some_and(|value| {
+        value.split(',').any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            params
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case(encoding))
+                && !params.any(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .is_some_and(|q| q.parse::<f32>().is_ok_and(|q| q <= 0.0))
+                })
+        })
+    })
+}
+
+pub(crate) fn compressible(config: &Config, content_type: Option<&str>) -> bool {
+    content_type.is_some_and(|value| {
+        let value = value.trim().to_ascii_lowercase();
+        config
+            .compressible_types
+            .iter()
+            .any(|prefix| value.starts_with(prefix.as_str()))
+    })
+}
+
+pub(crate) fn range_not_satisfiable(complete_length: usize) -> Response {
+    Response::from_status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .with_header("content-range", format!("bytes */{}", complete_length))
+        .with_body_text_plain("Range not satisfiable\n")
+}
+
+pub(crate) const GENERIC_CONTENT_TYPES: [&str; 3] = [
+    "application/octet-stream",
+    "binary/octet-stream",
+    "application/unknown",
+];
+
+pub(crate) fn set_range_headers(
+    resp: &mut Response,
+    req_range: &Option<RequestRange>,
+    range: &ResolvedRange,
+    complete_length: usize,
+    config: &Config,
+) {
+    resp.set_header("accept-ranges", "bytes");
+    if let Some(content_type) = config
+        .fallback_content_type
+        .as_ref()
+        .filter(|_| !no_transform(resp))
+    {
+        let generic = match header_str(resp.get_header("content-type")) {
+            Some(value) => GENERIC_CONTENT_TYPES
+                .iter()
+                .any(|generic| value.trim().eq_ignore_ascii_case(generic)),
+            None => true,
+        };
+        if generic {
+            resp.set_header("content-type", content_type);
+        }
+    }
+    if let Some(disposition) = &config.content_disposition {
+        resp.set_header("content-disposition", disposition);
+    }
+    if config.weak_etags {
+        let etag = header_str(resp.get_header("etag"))
+            .filter(|etag| !etag.starts_with("W/"))
+            .map(|etag| format!("W/{}", etag));
+        if let Some(etag) = etag {
+            resp.set_header("etag", etag);
+        }
+    }
+    // Clients that align their ranges to blocks hit cached fragments whole.
+    if config.advertise_block_size {
+        resp.set_header("x-sc-block-size", config.block_size.to_string());
+    }
+    if req_range.is_some() {
+        resp.set_status(StatusCode::PARTIAL_CONTENT);
+        resp.set_header(
+            "content-range",
+            format!("bytes {}-{}/{}", range.first, range.last, complete_length),
+        );
+    } else {
+        resp.set_status(StatusCode::OK);
+    }
+    resp.set_header("content-length", (range.last - range.first + 1).to_string());
+    resp.set_framing_headers_mode(fastly::http::FramingHeadersMode::ManuallyFromHeaders);
+}
+
+pub(crate) fn filter_response_headers(resp: &mut Response, config: &Config) {
+    // Cookies, internal headers and storage-provider metadata stay behind.
+    let names = resp
+        .get_header_names()
+        .map(|name| String::from(name.as_str()))
+        .filter(|name| {
+            !config
+                .forwarded_response_headers
+                .iter()
+                .any(|forwarded| forwarded.eq_ignore_ascii_case(name))
+        })
+        .collect::<Vec<_>>();
+    for name in names {
+        resp.remove_header(&name);
+    }
+}
+
+pub(crate) fn empty_object_response(beresp: &Response, ranged: bool, config: &Config) -> Response {
+    if ranged {
+        return Response::from_status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .with_header("content-range", "bytes */0")
+            .with_body_text_plain("Range not satisfiable\n");
+    }
+    let mut resp = beresp.clone_without_body();
+    resp.set_status(StatusCode::OK);
+    filter_response_headers(&mut resp, config);
+    resp
+}
+
+pub(crate) fn not_cached() -> Response {
+    Response::from_status(StatusCode::GATEWAY_TIMEOUT).with_body_text_plain("Not cached\n")
+}
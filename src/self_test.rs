@@ -0,0 +1,74 @@
+use crate::config::Config;
+use crate::headers::header_str;
+use crate::log::{millis, JsonRecord};
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::time::Instant;
+
+pub(crate) const SELF_TEST_PATH: &str = "/__sc/self-test";
+
+/// Fetches the configured test object through the service itself, checks its
+/// length and SHA-256, and reports how long the headers and the body took.
+/// The `server_timing` of the response breaks the fetch down further.
+pub(crate) fn self_test_response(config: &Config) -> Response {
+    let test = match &config.self_test {
+        Some(test) => test,
+        None => return Response::from_status(StatusCode::NOT_FOUND),
+    };
+    let mut record = JsonRecord::new();
+    record.str("object", &test.path);
+    let start = Instant::now();
+    let mut bereq = Request::new(Method::GET, format!("https://{}{}", test.host, test.path));
+    bereq.set_pass(true);
+    let mut beresp = match bereq.send(&test.backend) {
+        Ok(beresp) => beresp,
+        Err(e) => return report(record.str("error", &e.to_string()), false),
+    };
+    let headers = start.elapsed();
+    record.num("status", beresp.get_status().as_u16()).opt_str(
+        "server_timing",
+        header_str(beresp.get_header("server-timing")),
+    );
+    let mut body = beresp.take_body();
+    let mut hasher = Sha256::new();
+    let mut length = 0;
+    let mut buf = vec![0; config.read_chunk_size];
+    let error = loop {
+        match body.read(&mut buf) {
+            Ok(0) => break None,
+            Ok(rsize) => {
+                hasher.update(&buf[..rsize]);
+                length += rsize;
+            }
+            Err(e) => break Some(e.to_string()),
+        }
+    };
+    let sha256 = format!("{:x}", hasher.finalize());
+    let ok = error.is_none()
+        && beresp.get_status() == StatusCode::OK
+        && length == test.length
+        && sha256.eq_ignore_ascii_case(&test.sha256);
+    record
+        .num("length", length)
+        .num("expected_length", test.length)
+        .str("sha256", &sha256)
+        .str("expected_sha256", &test.sha256)
+        .opt_str("error", error.as_deref())
+        .raw("headers_ms", &millis(headers))
+        .raw("body_ms", &millis(start.elapsed() - headers));
+    report(&mut record, ok)
+}
+
+fn report(record: &mut JsonRecord, ok: bool) -> Response {
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Response::from_status(status)
+        .with_header("content-type", "application/json")
+        .with_header("cache-control", "no-store")
+        .with_body(record.num("ok", ok).finish())
+}
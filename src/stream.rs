@@ -0,0 +1,376 @@
+use crate::config::Config;
+use crate::content_range::ContentRange;
+use crate::error::SegmentedCacheError as Error;
+use crate::fetch::{fragment_from_response, FragmentFetcher};
+use crate::headers::{header_str, immutable, stale_if_error};
+use crate::log::with_log;
+use crate::range::ResolvedRange;
+use fastly::http::body::StreamingBody;
+use fastly::{Body, Response};
+use std::cmp::min;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+pub(crate) struct Fragment {
+    pub(crate) body: Body,
+    pub(crate) first: usize,
+    pub(crate) last: usize,
+    pub(crate) range_last: usize,
+    pub(crate) complete_length: usize,
+    pub(crate) content_length: Option<usize>,
+    pub(crate) validators: Validators,
+    pub(crate) stale_if_error: Option<Duration>,
+}
+
+impl Fragment {
+    pub(crate) fn new(resp: Response, content_range: &ContentRange) -> Self {
+        let content_length =
+            header_str(resp.get_header("content-length")).and_then(|value| value.parse().ok());
+        let validators = Validators::new(&resp);
+        let stale_if_error = stale_if_error(&resp);
+        Self {
+            body: resp.into_body(),
+            first: content_range.first,
+            last: content_range.last,
+            range_last: content_range.last,
+            complete_length: content_range.complete_length,
+            content_length,
+            validators,
+            stale_if_error,
+        }
+    }
+
+    pub(crate) fn trim(mut self, last: usize) -> Self {
+        // Origins that round ranges up to their own chunk boundaries send
+        // more than was asked for; only the requested window is forwarded.
+        self.last = min(self.last, last);
+        self
+    }
+
+    pub(crate) fn framed_length(&self) -> bool {
+        self.last == self.range_last && self.content_length == Some(self.last - self.first + 1)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Validators {
+    pub(crate) etag: Option<String>,
+    pub(crate) last_modified: Option<String>,
+    /// The origin promised the object never changes, so there is nothing to
+    /// compare.
+    pub(crate) immutable: bool,
+}
+
+impl Validators {
+    pub(crate) fn new(resp: &Response) -> Self {
+        Validators {
+            etag: header_str(resp.get_header("etag")).map(String::from),
+            last_modified: header_str(resp.get_header("last-modified")).map(String::from),
+            immutable: immutable(resp),
+        }
+    }
+
+    pub(crate) fn matches(&self, other: &Validators) -> bool {
+        fn same(a: &Option<String>, b: &Option<String>) -> bool {
+            match (a, b) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+        }
+        self.immutable
+            || other.immutable
+            || same(&self.etag, &other.etag) && same(&self.last_modified, &other.last_modified)
+    }
+}
+
+pub(crate) struct BodyStreamingState {
+    pub(crate) first: usize,
+    pub(crate) position: usize,
+    pub(crate) last: usize,
+    pub(crate) resp_body: StreamingBody,
+    pub(crate) buf: Vec<u8>,
+    pub(crate) wbuf: Vec<u8>,
+    pub(crate) write_flush_size: usize,
+    pub(crate) discarded: usize,
+    #[cfg(feature = "encryption")]
+    pub(crate) decryptor: Option<crate::encryption::Decryptor>,
+    #[cfg(feature = "integrity")]
+    pub(crate) verifier: Option<crate::integrity::Verifier>,
+}
+
+impl BodyStreamingState {
+    pub(crate) fn new(range: &ResolvedRange, resp_body: StreamingBody, config: &Config) -> Self {
+        BodyStreamingState {
+            first: range.first,
+            position: range.first,
+            last: range.last,
+            resp_body,
+            buf: vec![0; config.read_chunk_size],
+            wbuf: Vec::with_capacity(config.write_flush_size),
+            write_flush_size: config.write_flush_size,
+            discarded: 0,
+            #[cfg(feature = "encryption")]
+            decryptor: None,
+            #[cfg(feature = "integrity")]
+            verifier: None,
+        }
+    }
+
+    pub(crate) fn send_fragment(&mut self, mut frag: Fragment) -> Result<(), Error> {
+        if self.position < frag.first || self.position > frag.last {
+            return Err(Error::Inconsistency(format!(
+                "unexpected fragment {}-{} at position {}",
+                frag.first, frag.last, self.position
+            )));
+        }
+        if self.position > frag.first {
+            let mut remainder = self.position - frag.first;
+            while remainder > 0 {
+                let toread = min(remainder, self.buf.len());
+                let rsize = frag
+                    .body
+                    .read(&mut self.buf[..toread])
+                    .map_err(Error::Read)?;
+                if rsize == 0 {
+                    return Err(Error::Truncated {
+                        offset: self.position - remainder,
+                    });
+                }
+                remainder -= rsize;
+                self.discarded += rsize;
+            }
+        }
+        // The body is handed over unread only when its framing guarantees the
+        // declared length; otherwise it is counted to catch over-delivery.
+        if self.last >= frag.last && frag.framed_length() && !self.decrypting() && !self.verifying()
+        {
+            self.flush()?;
+            self.resp_body.append(frag.body);
+            self.position = frag.last + 1;
+        } else {
+            let last = min(frag.last, self.last);
+            let mut remainder = last - self.position + 1;
+            while remainder > 0 {
+                let toread = min(remainder, self.buf.len());
+                let rsize = frag
+                    .body
+                    .read(&mut self.buf[..toread])
+                    .map_err(Error::Read)?;
+                if rsize == 0 {
+                    return Err(Error::Truncated {
+                        offset: self.position,
+                    });
+                }
+                #[cfg(feature = "encryption")]
+                if let Some(decryptor) = &mut self.decryptor {
+                    decryptor.apply(self.position, &mut self.buf[..rsize]);
+                }
+                #[cfg(feature = "integrity")]
+                if let Some(verifier) = &mut self.verifier {
+                    verifier.update(&self.buf[..rsize]);
+                }
+                self.wbuf.extend_from_slice(&self.buf[..rsize]);
+                // The last bytes wait for the digest check.
+                if self.wbuf.len() >= self.write_flush_size
+                    && (self.position + rsize <= self.last || !self.verifying())
+                {
+                    self.flush()?;
+                }
+                remainder -= rsize;
+                self.position += rsize;
+            }
+            if last == frag.range_last
+                && frag.body.read(&mut self.buf[..1]).map_err(Error::Read)? > 0
+            {
+                return Err(Error::Inconsistency(format!(
+                    "fragment {}-{} longer than its content range",
+                    frag.first, frag.range_last
+                )));
+            }
+        }
+        #[cfg(feature = "integrity")]
+        if self.is_complete() {
+            if let Some(verifier) = self.verifier.take() {
+                verifier.verify().inspect_err(|_| self.wbuf.clear())?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    fn decrypting(&self) -> bool {
+        self.decryptor.is_some()
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn decrypting(&self) -> bool {
+        false
+    }
+
+    #[cfg(feature = "integrity")]
+    fn verifying(&self) -> bool {
+        self.verifier.is_some()
+    }
+
+    #[cfg(not(feature = "integrity"))]
+    fn verifying(&self) -> bool {
+        false
+    }
+
+    pub(crate) fn bytes_sent(&self) -> usize {
+        self.position - self.first
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.position > self.last
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<(), Error> {
+        let mut wpos = 0;
+        while wpos < self.wbuf.len() {
+            match self.resp_body.write(&self.wbuf[wpos..]) {
+                Ok(0) | Err(_) => {
+                    self.wbuf.clear();
+                    return Err(Error::ClientGone);
+                }
+                Ok(wsize) => wpos += wsize,
+            }
+        }
+        self.wbuf.clear();
+        Ok(())
+    }
+}
+
+pub(crate) fn send_with_resume(
+    state: &mut BodyStreamingState,
+    fetcher: &mut FragmentFetcher,
+    mut frag: Fragment,
+    complete_length: usize,
+) -> Result<(), Error> {
+    let first = frag.first;
+    let mut resumes = 0;
+    loop {
+        let last = frag.last;
+        let offset = match state.send_fragment(frag) {
+            Ok(()) => {
+                fetcher.config.hooks.on_fragment_complete(first, last);
+                return Ok(());
+            }
+            Err(Error::Truncated { offset }) if resumes < fetcher.config.max_resumes => offset,
+            Err(e) => return Err(e),
+        };
+        resumes += 1;
+        with_log(|log| log.retries += 1);
+        frag = fetcher.resume(offset, last, complete_length)?;
+    }
+}
+
+pub(crate) fn stream_fragments(
+    state: &mut BodyStreamingState,
+    fetcher: &mut FragmentFetcher,
+    frag1: Fragment,
+    complete_length: usize,
+) -> Result<(), Error> {
+    let validators = frag1.validators.clone();
+    let stale_window = frag1.stale_if_error;
+    let mut restarted = false;
+    let mut failures = 0;
+    send_with_resume(state, fetcher, frag1, complete_length)
+        .map_err(|e| e.context("sending first fragment"))?;
+    while !state.is_complete() {
+        let frag = match fetcher.next_response() {
+            Ok(Some((beresp, info))) => {
+                fragment_from_response(beresp, &info, complete_length, fetcher.config)
+            }
+            // Nothing more is coming, yet the range isn't complete.
+            Ok(None) => {
+                return Err(Error::Truncated {
+                    offset: state.position,
+                })
+            }
+            Err(e) => Err(e),
+        };
+        let result = match frag {
+            Ok(frag)
+                if frag.complete_length != complete_length
+                    || !frag.validators.matches(&validators) =>
+            {
+                // Bytes of another version can't be spliced into the response,
+                // so the rest is fetched again once and must match the original.
+                if !restarted {
+                    restarted = true;
+                    with_log(|log| log.retries += 1);
+                    fetcher.refetch(state.position, true)?;
+                    continue;
+                }
+                return Err(Error::Inconsistency(format!(
+                    "object changed between fragments: complete length {} vs {}, etag {:?} vs {:?}",
+                    frag.complete_length, complete_length, frag.validators.etag, validators.etag,
+                )));
+            }
+            Ok(frag) => {
+                let block_size = fetcher.frag_req_gen.block_size;
+                let block_last = state.position / block_size * block_size + block_size - 1;
+                send_with_resume(state, fetcher, frag.trim(block_last), complete_length)
+            }
+            Err(e) => Err(e),
+        };
+        // A stored copy of the block stands in for an origin that fails, for
+        // as long as the object's `stale-if-error` allows.
+        let result = match result {
+            Err(e) if origin_failed(&e) => match stale_window
+                .and_then(|window| fetcher.stale(state.position, window, complete_length))
+            {
+                Some(frag) => {
+                    with_log(|log| log.stale(state.position, &e));
+                    let block_size = fetcher.frag_req_gen.block_size;
+                    let block_last = state.position / block_size * block_size + block_size - 1;
+                    send_with_resume(state, fetcher, frag.trim(block_last), complete_length)
+                }
+                None => Err(e),
+            },
+            result => result,
+        };
+        match result {
+            Ok(()) => failures = 0,
+            Err(e @ (Error::ObjectShrank(_) | Error::ClientGone | Error::Integrity(_))) => {
+                return Err(e)
+            }
+            Err(e) => {
+                failures += 1;
+                with_log(|log| log.retries += 1);
+                if failures >= fetcher.config.degrade_after_failures {
+                    with_log(|log| log.degraded(state.position, failures, &e));
+                    return stream_remainder(state, fetcher, complete_length).map_err(|fallback| {
+                        fallback.context(format!("sequential fallback after {}", e))
+                    });
+                }
+                fetcher.refetch(state.position, false)?;
+            }
+        }
+        with_log(|log| log.bytes_sent = state.bytes_sent());
+    }
+    Ok(())
+}
+
+/// The origin failed to answer, which is what `stale-if-error` covers.
+fn origin_failed(e: &Error) -> bool {
+    match e {
+        Error::OriginStatus(status) => status.is_server_error(),
+        Error::Send { .. } | Error::Timeout { .. } => true,
+        Error::Context { source, .. } => origin_failed(source),
+        _ => false,
+    }
+}
+
+pub(crate) fn stream_remainder(
+    state: &mut BodyStreamingState,
+    fetcher: &mut FragmentFetcher,
+    complete_length: usize,
+) -> Result<(), Error> {
+    fetcher.cancel();
+    let frag = fetcher.resume(state.position, state.last, complete_length)?;
+    send_with_resume(state, fetcher, frag, complete_length)?;
+    with_log(|log| log.bytes_sent = state.bytes_sent());
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use crate::archive::ArchiveReader;
+use crate::error::SegmentedCacheError as Error;
+
+const HEADER_SIZE: usize = 512;
+
+/// Lists the regular files of the archive, one `offset size name` line
+/// each, with the offset of the member's data. GNU long names and pax
+/// `path` and `size` records are followed.
+pub(crate) fn build_index(reader: &mut ArchiveReader) -> Result<String, Error> {
+    let mut index = String::new();
+    let mut position = 0;
+    let mut long_name = None;
+    let mut pax = Vec::new();
+    while reader.complete_length.saturating_sub(position) >= HEADER_SIZE {
+        let header = reader.read(position, HEADER_SIZE)?;
+        // The archive ends with zeroed blocks.
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let pax_value = |key: &str| {
+            pax.iter()
+                .rev()
+                .find(|(name, _): &&(String, String)| name == key)
+                .map(|(_, value)| value.clone())
+        };
+        let pax_path = pax_value("path");
+        let size = match pax_value("size") {
+            Some(size) => size.parse().ok(),
+            None => header_number(&header[124..136]),
+        }
+        .ok_or(Error::Inconsistency(format!(
+            "invalid tar header at {}",
+            position
+        )))?;
+        let data = position + HEADER_SIZE;
+        match header[156] {
+            b'L' | b'x' => {
+                if size > reader.config.block_size {
+                    return Err(Error::Inconsistency(format!(
+                        "oversized tar extended header at {}",
+                        position
+                    )));
+                }
+                let body = reader.read(data, size)?;
+                if header[156] == b'L' {
+                    long_name = Some(header_str(&body));
+                } else {
+                    pax = pax_records(&body);
+                }
+            }
+            typeflag => {
+                if matches!(typeflag, b'0' | b'\0' | b'7') {
+                    let name = match (long_name.take(), pax_path) {
+                        (_, Some(path)) => path,
+                        (Some(name), None) => name,
+                        (None, None) => {
+                            let name = header_str(&header[0..100]);
+                            let prefix = header_str(&header[345..500]);
+                            if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                                format!("{}/{}", prefix, name)
+                            } else {
+                                name
+                            }
+                        }
+                    };
+                    let name = name.trim_start_matches("./");
+                    if !name.contains('\n') {
+                        index.push_str(&format!("{} {} {}\n", data, size, name));
+                    }
+                }
+                long_name = None;
+                pax.clear();
+            }
+        }
+        // A pax size can be anything, so it mustn't wrap the position.
+        position = size
+            .div_ceil(HEADER_SIZE)
+            .checked_mul(HEADER_SIZE)
+            .and_then(|padded| data.checked_add(padded))
+            .ok_or_else(|| {
+                Error::Inconsistency(format!("tar member size overflows at {}", position))
+            })?;
+    }
+    Ok(index)
+}
+
+fn header_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// An octal field, or a base-256 one when the high bit of its first byte is
+/// set, as for sizes of 8 GiB and more.
+fn header_number(field: &[u8]) -> Option<usize> {
+    if field[0] & 0x80 != 0 {
+        return field[1..]
+            .iter()
+            .try_fold(usize::from(field[0] & 0x7f), |n, &b| {
+                n.checked_mul(256)?.checked_add(usize::from(b))
+            });
+    }
+    let digits = header_str(field);
+    let digits = digits.trim_matches(' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    usize::from_str_radix(digits, 8).ok()
+}
+
+/// The records of a pax extended header, each `length key=value\n`.
+fn pax_records(mut body: &[u8]) -> Vec<(String, String)> {
+    let mut records = Vec::new();
+    while let Some(space) = body.iter().position(|&b| b == b' ') {
+        let length = match std::str::from_utf8(&body[..space])
+            .ok()
+            .and_then(|length| length.parse::<usize>().ok())
+        {
+            Some(length) if length > space && length <= body.len() => length,
+            _ => break,
+        };
+        let record = String::from_utf8_lossy(&body[space + 1..length]);
+        if let Some((key, value)) = record.trim_end_matches('\n').split_once('=') {
+            records.push((String::from(key), String::from(value)));
+        }
+        body = &body[length..];
+    }
+    records
+}
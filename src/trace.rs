@@ -0,0 +1,67 @@
+use crate::headers::header_str;
+use crate::log::random_u64;
+use fastly::Request;
+
+pub(crate) fn request_id(req: &Request) -> String {
+    match header_str(req.get_header("x-request-id")) {
+        Some(id)
+            if !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()) =>
+        {
+            id.to_string()
+        }
+        _ => format!("{:016x}{:016x}", random_u64(), random_u64()),
+    }
+}
+
+pub(crate) struct TraceContext {
+    pub(crate) trace_id: String,
+    pub(crate) parent_id: Option<String>,
+    pub(crate) flags: String,
+}
+
+impl TraceContext {
+    pub(crate) fn new(req: &Request) -> Self {
+        if let Some(trace) = header_str(req.get_header("traceparent")).and_then(Self::parse) {
+            return trace;
+        }
+        TraceContext {
+            trace_id: format!("{:016x}{:016x}", random_u64(), random_u64()),
+            parent_id: None,
+            flags: String::from("01"),
+        }
+    }
+
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        let parts = value.trim().split('-').collect::<Vec<_>>();
+        let (version, trace_id, parent_id, flags) = match &parts[..] {
+            [version, trace_id, parent_id, flags, ..] => (*version, *trace_id, *parent_id, *flags),
+            _ => return None,
+        };
+        let is_hex = |s: &str, len| {
+            s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        };
+        if !is_hex(version, 2) || version == "ff" || (version == "00" && parts.len() != 4) {
+            return None;
+        }
+        if !is_hex(trace_id, 32) || trace_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+        if !is_hex(parent_id, 16) || parent_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+        if !is_hex(flags, 2) {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id: trace_id.to_string(),
+            parent_id: Some(parent_id.to_string()),
+            flags: flags.to_string(),
+        })
+    }
+
+    pub(crate) fn child(&self) -> (String, String) {
+        let span_id = format!("{:016x}", random_u64() | 1);
+        let traceparent = format!("00-{}-{}-{}", self.trace_id, span_id, self.flags);
+        (span_id, traceparent)
+    }
+}
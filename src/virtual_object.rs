@@ -0,0 +1,108 @@
+use crate::config::Config;
+use crate::error::SegmentedCacheError as Error;
+use crate::fetch::{fragment_from_response, FragReqGen, FragmentFetcher};
+use crate::log::with_log;
+use crate::prewarm::{object_request, parse_prewarm_list};
+use crate::range::{RequestRange, ResolvedRange};
+use crate::response::{range_not_satisfiable, set_range_headers, set_response_headers};
+use crate::stream::{stream_fragments, BodyStreamingState};
+use crate::trace::TraceContext;
+use fastly::http::StatusCode;
+use fastly::{Request, Response};
+use std::cmp::{max, min};
+
+/// Serves the concatenation of the objects a manifest lists, in the
+/// prewarm list format with every size given. A range fetches the blocks
+/// of only the parts it overlaps, each streamed like a range of an object.
+pub(crate) fn virtual_response(
+    req: &Request,
+    config: &Config,
+    trace: &TraceContext,
+    manifest: &str,
+    header_only: bool,
+    resp_header_sent: &mut bool,
+    resp_headers: &[(String, String)],
+) -> Result<Option<Response>, Error> {
+    let parts = parse_prewarm_list(manifest)
+        .and_then(|parts| {
+            parts
+                .into_iter()
+                .map(|part| Some((part.url, part.size?)))
+                .collect::<Option<Vec<_>>>()
+                .ok_or("part without a size")
+        })
+        .map_err(|e| Error::Inconsistency(format!("virtual object manifest: {}", e)))?;
+    let complete_length = parts.iter().map(|(_, size)| size).sum::<usize>();
+
+    let mut resp = Response::from_status(StatusCode::OK);
+    resp.set_header(
+        "content-type",
+        config
+            .fallback_content_type
+            .as_deref()
+            .unwrap_or("application/octet-stream"),
+    );
+    if complete_length == 0 {
+        resp.set_header("content-length", "0");
+        return Ok(Some(resp));
+    }
+    let req_range = RequestRange::new(req, config)?;
+    let range = match ResolvedRange::new(&req_range, complete_length) {
+        Some(range) => range,
+        None => return Ok(Some(range_not_satisfiable(complete_length))),
+    };
+    set_range_headers(&mut resp, &req_range, &range, complete_length, config);
+    if header_only {
+        return Ok(Some(resp));
+    }
+    resp.set_header("x-request-id", with_log(|log| log.request_id.clone()));
+    set_response_headers(&mut resp, resp_headers);
+    config.hooks.on_response_headers(&mut resp);
+    with_log(|log| log.status = Some(resp.get_status()));
+    let mut resp_body = resp.stream_to_client();
+    *resp_header_sent = true;
+
+    let mut offset = 0;
+    let mut sent = 0;
+    for (url, size) in parts {
+        let part_offset = offset;
+        offset += size;
+        if size == 0 || range.first >= offset || range.last < part_offset {
+            continue;
+        }
+        // The part's share of the range, in the part's own offsets.
+        let part_range = ResolvedRange {
+            first: max(range.first, part_offset) - part_offset,
+            last: min(range.last, offset - 1) - part_offset,
+        };
+        let part = object_request(req, &url);
+        let block_first = part_range.first / config.block_size * config.block_size;
+        let mut fetcher = FragmentFetcher::new(
+            &part,
+            config,
+            trace,
+            FragReqGen::new(block_first, part_range.last, config),
+        );
+        fetcher.dispatch()?;
+        let frag1 = match fetcher.next_response()? {
+            Some((beresp, info)) => fragment_from_response(beresp, &info, size, config)?,
+            None => return Err(Error::Inconsistency(String::from("no fragment for part"))),
+        };
+        if frag1.complete_length != size {
+            return Err(Error::Inconsistency(format!(
+                "part {} is {} bytes rather than the listed {}",
+                url, frag1.complete_length, size
+            )));
+        }
+        let mut state = BodyStreamingState::new(&part_range, resp_body, config);
+        let result = stream_fragments(&mut state, &mut fetcher, frag1, size);
+        fetcher.cancel();
+        let result = result.and(state.flush());
+        sent += state.bytes_sent();
+        with_log(|log| log.bytes_sent = sent);
+        result.map_err(|e| e.context(format!("part {}", url)))?;
+        resp_body = state.resp_body;
+    }
+    let _ = resp_body.finish();
+    Ok(None)
+}
@@ -0,0 +1,131 @@
+use crate::archive::ArchiveReader;
+use crate::error::SegmentedCacheError as Error;
+use std::cmp::min;
+use std::convert::TryFrom;
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const ZIP64_END_LOCATOR: u32 = 0x0706_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x0606_4b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x0201_4b50;
+const LOCAL_FILE_HEADER: u32 = 0x0403_4b50;
+
+/// Lists the stored members of the archive, one `offset size name` line
+/// each, with the offset of the member's local header. The central
+/// directory is found from the end record in the archive's tail. Members
+/// that are compressed or encrypted can't be served as ranges of the
+/// archive, so they are left out.
+pub(crate) fn build_index(reader: &mut ArchiveReader) -> Result<String, Error> {
+    let invalid = |what: &str| Error::Inconsistency(format!("invalid ZIP archive: {}", what));
+    // The end record is 22 bytes and may be followed by a comment of up to
+    // 64 KiB.
+    let tail_first = reader.complete_length.saturating_sub(22 + 65535);
+    let tail = reader.read(tail_first, reader.complete_length - tail_first)?;
+    let end = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&at| le32(&tail, at) == END_OF_CENTRAL_DIRECTORY)
+        .ok_or_else(|| invalid("no end of central directory"))?;
+    let mut directory_size = le32(&tail, end + 12) as usize;
+    let mut directory_offset = le32(&tail, end + 16) as usize;
+    if directory_offset == 0xffff_ffff || directory_size == 0xffff_ffff {
+        let locator = end
+            .checked_sub(20)
+            .filter(|&at| le32(&tail, at) == ZIP64_END_LOCATOR)
+            .ok_or_else(|| invalid("no ZIP64 end locator"))?;
+        let record_offset =
+            le64_usize(&tail, locator + 8).ok_or_else(|| invalid("ZIP64 end locator"))?;
+        let record = reader.read(record_offset, 56)?;
+        if le32(&record, 0) != ZIP64_END_OF_CENTRAL_DIRECTORY {
+            return Err(invalid("no ZIP64 end of central directory"));
+        }
+        directory_size =
+            le64_usize(&record, 40).ok_or_else(|| invalid("ZIP64 central directory size"))?;
+        directory_offset =
+            le64_usize(&record, 48).ok_or_else(|| invalid("ZIP64 central directory offset"))?;
+    }
+    if directory_offset
+        .checked_add(directory_size)
+        .is_none_or(|end| end > reader.complete_length)
+    {
+        return Err(invalid("central directory out of bounds"));
+    }
+    let directory = reader.read(directory_offset, directory_size)?;
+
+    let mut index = String::new();
+    let mut at = 0;
+    while at + 46 <= directory.len() && le32(&directory, at) == CENTRAL_DIRECTORY_HEADER {
+        let flags = le16(&directory, at + 8);
+        let method = le16(&directory, at + 10);
+        let mut size = le32(&directory, at + 24) as usize;
+        let name_len = le16(&directory, at + 28) as usize;
+        let extra_len = le16(&directory, at + 30) as usize;
+        let comment_len = le16(&directory, at + 32) as usize;
+        let mut offset = le32(&directory, at + 42) as usize;
+        let next = at + 46 + name_len + extra_len + comment_len;
+        if next > directory.len() {
+            return Err(invalid("truncated central directory"));
+        }
+        let name = String::from_utf8_lossy(&directory[at + 46..at + 46 + name_len]);
+        let compressed_size = le32(&directory, at + 20) as usize;
+        // ZIP64 sizes and offsets are in an extra field, in this order,
+        // for each of the fixed fields that is saturated.
+        let mut extra = &directory[at + 46 + name_len..at + 46 + name_len + extra_len];
+        while extra.len() >= 4 {
+            let (id, len) = (le16(extra, 0), le16(extra, 2) as usize);
+            let field = &extra[4..min(4 + len, extra.len())];
+            if id == 0x0001 {
+                let mut values = field.chunks_exact(8).map(|value| le64_usize(value, 0));
+                let too_large = || invalid("ZIP64 member size or offset");
+                if size == 0xffff_ffff {
+                    if let Some(value) = values.next() {
+                        size = value.ok_or_else(too_large)?;
+                    }
+                }
+                if compressed_size == 0xffff_ffff {
+                    values.next();
+                }
+                if offset == 0xffff_ffff {
+                    if let Some(value) = values.next() {
+                        offset = value.ok_or_else(too_large)?;
+                    }
+                }
+            }
+            extra = &extra[min(4 + len, extra.len())..];
+        }
+        if method == 0 && flags & 1 == 0 && !name.ends_with('/') && !name.contains('\n') {
+            index.push_str(&format!("{} {} {}\n", offset, size, name));
+        }
+        at = next;
+    }
+    Ok(index)
+}
+
+/// Where the data of the member whose local header is at `offset` starts.
+pub(crate) fn data_offset(reader: &mut ArchiveReader, offset: usize) -> Result<usize, Error> {
+    let header = reader.read(offset, 30)?;
+    if le32(&header, 0) != LOCAL_FILE_HEADER {
+        return Err(Error::Inconsistency(format!(
+            "no ZIP local file header at {}",
+            offset
+        )));
+    }
+    Ok(offset + 30 + le16(&header, 26) as usize + le16(&header, 28) as usize)
+}
+
+fn le16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn le32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn le64(bytes: &[u8], at: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(value)
+}
+
+/// A ZIP64 size or offset, which may not fit in a `usize`.
+fn le64_usize(bytes: &[u8], at: usize) -> Option<usize> {
+    usize::try_from(le64(bytes, at)).ok()
+}
@@ -0,0 +1,178 @@
+//! End-to-end tests against the service built with the `mock-origin`
+//! feature, which is its own origin. They run under Viceroy and send their
+//! requests through the `mock_origin` backend, so the service has to be
+//! served on it first:
+//!
+//!     cargo build --features mock-origin
+//!     viceroy -C fastly.toml target/wasm32-wasi/debug/fastly-compute-project.wasm &
+//!     cargo nextest run --features mock-origin --test mock_origin
+#![cfg(feature = "mock-origin")]
+
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use std::io::Read;
+
+/// Five blocks of the default size, the last one short.
+const SIZE: usize = 5_000_000;
+
+fn request(method: Method, query: &str, range: Option<&str>) -> Request {
+    let url = format!("http://127.0.0.1:7676/obj?size={}{}", SIZE, query);
+    let mut req = Request::new(method, url);
+    if let Some(range) = range {
+        req.set_header("range", range);
+    }
+    req
+}
+
+fn send(req: Request) -> Response {
+    req.send("mock_origin")
+        .expect("the mock-origin service isn't served on 127.0.0.1:7676")
+}
+
+fn get(query: &str, range: Option<&str>) -> Response {
+    send(request(Method::GET, query, range))
+}
+
+/// Bytes `first..=last` of the mock object.
+fn content(first: usize, last: usize) -> Vec<u8> {
+    (first..=last).map(|i| (i % 251) as u8).collect()
+}
+
+/// The body as far as it goes, which is short of its end when the response
+/// was aborted.
+fn body(resp: Response) -> Vec<u8> {
+    let mut body = Vec::new();
+    let _ = resp.into_body().read_to_end(&mut body);
+    body
+}
+
+fn assert_range(resp: Response, first: usize, last: usize) {
+    assert_eq!(resp.get_status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        resp.get_header_str("content-range"),
+        Some(format!("bytes {}-{}/{}", first, last, SIZE).as_str())
+    );
+    assert!(body(resp) == content(first, last), "wrong bytes");
+}
+
+/// Asserts the response was cut short rather than given bytes that don't
+/// belong to the object.
+fn assert_aborted(resp: Response) {
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    let body = body(resp);
+    assert!(body.len() < SIZE, "failure not surfaced");
+    assert!(body[..] == content(0, SIZE - 1)[..body.len()], "wrong bytes");
+}
+
+#[test]
+fn full_transfer() {
+    let resp = get("", None);
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    assert_eq!(
+        resp.get_header_str("content-length"),
+        Some(SIZE.to_string().as_str())
+    );
+    assert!(body(resp) == content(0, SIZE - 1), "wrong bytes");
+}
+
+#[test]
+fn full_transfer_at_every_parallelism_and_block_size() {
+    for conf in ["p=1", "p=3,b=1048577", "p=10,b=2097152"] {
+        let mut req = request(Method::GET, "", Some("bytes=0-"));
+        req.set_header("x-sc-conf", conf);
+        assert_range(send(req), 0, SIZE - 1);
+    }
+}
+
+#[test]
+fn misaligned_range() {
+    assert_range(get("", Some("bytes=1000-3000000")), 1000, 3000000);
+}
+
+#[test]
+fn range_across_a_block_boundary() {
+    assert_range(get("", Some("bytes=1048570-1048580")), 1048570, 1048580);
+}
+
+#[test]
+fn open_ended_range() {
+    assert_range(get("", Some("bytes=4000001-")), 4000001, SIZE - 1);
+}
+
+#[test]
+fn range_past_the_end() {
+    let resp = get("", Some("bytes=6000000-"));
+    assert_eq!(resp.get_status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(
+        resp.get_header_str("content-range"),
+        Some(format!("bytes */{}", SIZE).as_str())
+    );
+}
+
+#[test]
+fn head() {
+    let resp = send(request(Method::HEAD, "", None));
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    assert_eq!(
+        resp.get_header_str("content-length"),
+        Some(SIZE.to_string().as_str())
+    );
+    assert!(body(resp).is_empty());
+}
+
+#[test]
+fn origin_ignoring_the_range() {
+    assert_range(get("&ignore_range", Some("bytes=1000-3000000")), 1000, 3000000);
+}
+
+#[test]
+fn origin_not_knowing_the_length() {
+    assert_range(
+        get("&unknown_length", Some("bytes=1000-3000000")),
+        1000,
+        3000000,
+    );
+}
+
+#[test]
+fn origin_never_telling_the_length() {
+    let resp = get("&unknown_length&no_head", Some("bytes=1000-3000000"));
+    assert_eq!(resp.get_status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        resp.get_header_str("content-range"),
+        Some("bytes 1000-3000000/*")
+    );
+    assert!(body(resp) == content(1000, 3000000), "wrong bytes");
+
+    let resp = get("&unknown_length&no_head", None);
+    assert_eq!(resp.get_status(), StatusCode::OK);
+    assert_eq!(resp.get_header_str("content-length"), None);
+    assert!(body(resp) == content(0, SIZE - 1), "wrong bytes");
+}
+
+#[test]
+fn failed_first_fragment() {
+    let resp = get("&fail_at=0&fail_status=404", None);
+    assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn failed_fragment() {
+    assert_aborted(get("&fail_at=3000000", None));
+}
+
+#[test]
+fn truncated_fragment() {
+    assert_aborted(get("&truncate_at=2500000", None));
+}
+
+#[test]
+fn object_changed_between_fragments() {
+    assert_aborted(get("&change_at=2000000", None));
+}
+
+#[test]
+fn purge_needs_the_debug_token() {
+    let req = Request::new(Method::POST, "http://127.0.0.1:7676/__sc/purge?url=/obj");
+    assert_eq!(send(req).get_status(), StatusCode::NOT_FOUND);
+}